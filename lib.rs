@@ -2,12 +2,16 @@
 
 #[ink::contract]
 mod marketplace {
+    use ink::env::call::{build_call, ExecutionInput, Selector};
+    use ink::env::hash::Blake2x256;
     use ink::prelude::string::String;
     use ink::prelude::vec::Vec;
+    use ink::storage::traits::StorageKey;
     use ink::storage::Mapping;
 
     #[ink::scale_derive(Encode, Decode, TypeInfo)]
     #[derive(Debug, PartialEq, Eq)]
+    #[allow(clippy::cast_possible_truncation)]
     pub enum MarketplaceError {
         UserAlreadyExists,
         InvalidUser,
@@ -20,10 +24,180 @@ mod marketplace {
         OfferAlreadyAccepted,
         RequestNotAccepted,
         RequestNotLocked,
+        InsufficientDeposit,
+        IncorrectDeposit,
+        TransferFailed,
+        TokenTransferFailed,
+        NotOwner,
+        NotArbitrator,
+        NotRequestParticipant,
+        RequestNotDisputed,
+        AlreadyVoted,
+        RequestNotCompleted,
+        InvalidRating,
+        AlreadyReviewed,
+        InvalidFee,
+        InvalidExpiry,
+        RequestExpired,
+        RequestNotExpired,
+        UnauthorizedSeller,
+        DuplicateOffer,
+        CounterOfferPending,
+        NoCounterOffer,
+        NegotiationLimitReached,
+        WrongBiddingMode,
+        InvalidBiddingWindow,
+        BiddingWindowClosed,
+        BiddingInProgress,
+        NoCommitment,
+        InvalidReveal,
+        InvalidReservePrice,
+        StoreNotFound,
+        StoreInactive,
+        ListingNotFound,
+        InvalidQuantity,
+        OutOfStock,
+        InvalidCategory,
+        InvalidTags,
+        InvalidSubscription,
+        SubscriptionNotFound,
+        InvalidLocation,
+        ContactKeyMissing,
+        InvalidContact,
+        MissingRole,
+        ContractPaused,
+        InvalidConfig,
+        TooManyImages,
+        TooManyOffers,
+        OfferPriceChanged,
+        FixedPriceOffer,
+        PhoneNotMasked,
+        AlreadyOwner,
+        PartyToDispute,
+        NoArbitrators,
+        DisputeNotExpired,
+        InvalidLifecycleTransition {
+            from: RequestLifecycle,
+            to: RequestLifecycle,
+        },
     }
 
     pub type Result<T> = core::result::Result<T, MarketplaceError>;
 
+    // Latest ratings that make up a user's recent average
+    const RECENT_RATINGS_WINDOW: usize = 10;
+
+    const MAX_NEGOTIATION_ROUNDS: u8 = 5;
+
+    const BASIS_POINTS: u16 = 10_000;
+
+    // 15 minutes, in milliseconds
+    const DEFAULT_TIME_TO_LOCK: u64 = 900 * 1000;
+
+    // Bounds of the lock window: one minute to 30 days
+    const MIN_TIME_TO_LOCK: u64 = 60 * 1000;
+    const MAX_TIME_TO_LOCK: u64 = 30 * 24 * 60 * 60 * 1000;
+
+    const MAX_IMAGES_PER_REQUEST: u32 = 20;
+
+    // 14 days, in milliseconds
+    const DISPUTE_TIMEOUT: u64 = 14 * 24 * 60 * 60 * 1000;
+
+    const MAX_TAGS: usize = 5;
+
+    const MAX_TAG_LENGTH: usize = 32;
+
+    // Coordinates are fixed-point microdegrees
+    const COORDINATE_SCALE: i128 = 1_000_000;
+
+    const MAX_LATITUDE: i128 = 90 * COORDINATE_SCALE;
+    const MAX_LONGITUDE: i128 = 180 * COORDINATE_SCALE;
+
+    // Length of one degree of latitude, in meters
+    const METERS_PER_DEGREE: i128 = 111_195;
+
+    const MAX_SUBSCRIPTION_CATEGORIES: usize = 10;
+
+    // Larger categories are checked in turns, see `notify_subscribers`
+    const MAX_SUBSCRIBERS_CHECKED: u64 = 50;
+
+    const MATCH_INBOX_SIZE: usize = 50;
+
+    const MAX_CONTACT_LENGTH: usize = 512;
+
+    const VISIBLE_PHONE_DIGITS: usize = 2;
+
+    // 12 + 13 bits (a 5-character geohash) give cells of about 4.9 km
+    const CELL_LATITUDE_BITS: u32 = 12;
+    const CELL_LONGITUDE_BITS: u32 = 13;
+
+    // In meters
+    const MAX_SEARCH_RADIUS: u64 = 10_000;
+
+    // Beyond about 82° of latitude a query no longer spans its whole radius
+    const MAX_CELL_SPAN: i128 = 16;
+
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[derive(Debug, PartialEq, Eq)]
+    // The derived codec impls cast variant indexes to u8
+    #[allow(clippy::cast_possible_truncation)]
+    pub enum Psp22Error {
+        Custom(String),
+        InsufficientBalance,
+        InsufficientAllowance,
+        ZeroRecipientAddress,
+        ZeroSenderAddress,
+        SafeTransferCheckFailed(String),
+    }
+
+    #[derive(Clone, Copy, PartialEq, Default)]
+    #[cfg_attr(
+        feature = "std",
+        derive(Debug, Eq, ink::storage::traits::StorageLayout)
+    )]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub enum BiddingMode {
+        #[default]
+        Open,
+        // Sellers commit to blake2_256((request_id, seller_account, price, salt))
+        Sealed {
+            commit_ends_at: u64,
+            reveal_ends_at: u64,
+        },
+        ReverseAuction {
+            closes_at: u64,
+            reserve_price: Balance,
+        },
+    }
+
+    #[derive(Clone, Default)]
+    #[cfg_attr(
+        feature = "std",
+        derive(Debug, PartialEq, Eq, ink::storage::traits::StorageLayout)
+    )]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub struct RequestParams {
+        pub payment_asset: PaymentAsset,
+        pub expires_at: Option<u64>,
+        pub bidding_mode: BiddingMode,
+        pub category_id: Option<u64>,
+        pub tags: Vec<String>,
+    }
+
+    #[derive(Clone, Copy, PartialEq, Default)]
+    #[cfg_attr(
+        feature = "std",
+        derive(Debug, Eq, ink::storage::traits::StorageLayout)
+    )]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub enum PaymentAsset {
+        #[default]
+        Native,
+        Psp22(AccountId),
+    }
+
     #[derive(Clone)]
     #[cfg_attr(
         feature = "std",
@@ -35,6 +209,164 @@ mod marketplace {
         longitude: i128,
     }
 
+    impl Location {
+        pub fn new(latitude: i128, longitude: i128) -> Result<Self> {
+            if !(-90 * COORDINATE_SCALE..=90 * COORDINATE_SCALE).contains(&latitude)
+                || !(-180 * COORDINATE_SCALE..=180 * COORDINATE_SCALE).contains(&longitude)
+            {
+                return Err(MarketplaceError::InvalidLocation);
+            }
+            Ok(Self {
+                latitude,
+                longitude,
+            })
+        }
+
+        // Equirectangular approximation, in meters
+        pub fn distance_to(&self, other: &Location) -> u64 {
+            let lat_a = self.latitude.clamp(-MAX_LATITUDE, MAX_LATITUDE);
+            let lat_b = other.latitude.clamp(-MAX_LATITUDE, MAX_LATITUDE);
+            let lon_a = self.longitude.clamp(-MAX_LONGITUDE, MAX_LONGITUDE);
+            let lon_b = other.longitude.clamp(-MAX_LONGITUDE, MAX_LONGITUDE);
+
+            let d_lat = lat_b.saturating_sub(lat_a).abs();
+            let mut d_lon = lon_b.saturating_sub(lon_a).abs();
+            if d_lon > MAX_LONGITUDE {
+                d_lon = (2 * MAX_LONGITUDE).saturating_sub(d_lon);
+            }
+
+            let cos_latitude = cos_latitude(lat_a.saturating_add(lat_b) / 2);
+            let dy = d_lat.saturating_mul(METERS_PER_DEGREE) / COORDINATE_SCALE;
+            let dx = (d_lon.saturating_mul(METERS_PER_DEGREE) / COORDINATE_SCALE)
+                .saturating_mul(cos_latitude)
+                / COORDINATE_SCALE;
+            let distance = isqrt(
+                dx.saturating_mul(dx)
+                    .saturating_add(dy.saturating_mul(dy))
+                    .unsigned_abs(),
+            );
+            u64::try_from(distance).unwrap_or(u64::MAX)
+        }
+
+        // Interleaved longitude and latitude cell bits, longitude first
+        pub fn cell(&self) -> u64 {
+            let (row, column) = self.cell_indexes();
+            geohash_cell(row, column)
+        }
+
+        fn cells_within(&self, radius: u64) -> Vec<u64> {
+            let rows: i128 = 1 << CELL_LATITUDE_BITS;
+            let columns: i128 = 1 << CELL_LONGITUDE_BITS;
+            let cell_height = 180 * METERS_PER_DEGREE / rows;
+            let cell_width = ((360 * METERS_PER_DEGREE / columns)
+                .saturating_mul(cos_latitude(self.latitude))
+                / COORDINATE_SCALE)
+                .max(1);
+
+            let (row, column) = self.cell_indexes();
+            let row_span = i128::from(radius)
+                .checked_div(cell_height)
+                .unwrap()
+                .saturating_add(1);
+            let column_span = i128::from(radius)
+                .checked_div(cell_width)
+                .unwrap()
+                .saturating_add(1)
+                .min(MAX_CELL_SPAN);
+
+            let mut cells = Vec::new();
+            for r in
+                row.saturating_sub(row_span).max(0)..=row.saturating_add(row_span).min(rows - 1)
+            {
+                for c in column.saturating_sub(column_span)..=column.saturating_add(column_span) {
+                    cells.push(geohash_cell(r, c.rem_euclid(columns)));
+                }
+            }
+            cells
+        }
+
+        // Center of the cell, shown for users who disabled their location
+        pub fn coarsened(&self) -> Location {
+            let rows: i128 = 1 << CELL_LATITUDE_BITS;
+            let columns: i128 = 1 << CELL_LONGITUDE_BITS;
+
+            let (row, column) = self.cell_indexes();
+            Location {
+                latitude: row
+                    .saturating_mul(2)
+                    .saturating_add(1)
+                    .saturating_mul(MAX_LATITUDE)
+                    .checked_div(rows)
+                    .unwrap()
+                    .saturating_sub(MAX_LATITUDE),
+                longitude: column
+                    .saturating_mul(2)
+                    .saturating_add(1)
+                    .saturating_mul(MAX_LONGITUDE)
+                    .checked_div(columns)
+                    .unwrap()
+                    .saturating_sub(MAX_LONGITUDE),
+            }
+        }
+
+        fn cell_indexes(&self) -> (i128, i128) {
+            let rows: i128 = 1 << CELL_LATITUDE_BITS;
+            let columns: i128 = 1 << CELL_LONGITUDE_BITS;
+
+            let latitude = self.latitude.clamp(-MAX_LATITUDE, MAX_LATITUDE);
+            let longitude = self.longitude.clamp(-MAX_LONGITUDE, MAX_LONGITUDE);
+            let row = (latitude.saturating_add(MAX_LATITUDE).saturating_mul(rows)
+                / (2 * MAX_LATITUDE))
+                .min(rows - 1);
+            let column = (longitude
+                .saturating_add(MAX_LONGITUDE)
+                .saturating_mul(columns)
+                / (2 * MAX_LONGITUDE))
+                .min(columns - 1);
+            (row, column)
+        }
+    }
+
+    fn geohash_cell(row: i128, column: i128) -> u64 {
+        let mut cell = 0u64;
+        for bit in (0..CELL_LONGITUDE_BITS).rev() {
+            cell = (cell << 1) | u64::from((column >> bit) & 1 == 1);
+            if let Some(row_bit) = bit.checked_sub(1) {
+                cell = (cell << 1) | u64::from((row >> row_bit) & 1 == 1);
+            }
+        }
+        cell
+    }
+
+    // Bhaskara I's approximation, scaled by `COORDINATE_SCALE`
+    fn cos_latitude(latitude: i128) -> i128 {
+        let half_turn_sq = (2 * MAX_LATITUDE).pow(2);
+        let latitude_sq = latitude.clamp(-MAX_LATITUDE, MAX_LATITUDE).pow(2);
+        half_turn_sq
+            .saturating_sub(latitude_sq.saturating_mul(4))
+            .saturating_mul(COORDINATE_SCALE)
+            .checked_div(half_turn_sq.saturating_add(latitude_sq))
+            .unwrap()
+    }
+
+    fn is_masked_phone(phone: &str) -> bool {
+        let visible_from = phone.chars().count().saturating_sub(VISIBLE_PHONE_DIGITS);
+        phone.chars().take(visible_from).all(|c| c == '*')
+    }
+
+    fn isqrt(n: u128) -> u128 {
+        if n < 2 {
+            return n;
+        }
+        let mut x = n;
+        let mut y = x.div_ceil(2);
+        while y < x {
+            x = y;
+            y = x.saturating_add(n.checked_div(x).unwrap()) / 2;
+        }
+        x
+    }
+
     #[derive(Clone)]
     #[cfg_attr(
         feature = "std",
@@ -46,7 +378,76 @@ mod marketplace {
         name: String,
         description: String,
         phone: String,
+        phone_commitment: Hash,
         location: Location,
+        is_active: bool,
+    }
+
+    // Call arguments are public, so clients mask the phone and commit to the number
+    #[derive(Clone)]
+    #[cfg_attr(
+        feature = "std",
+        derive(Debug, PartialEq, Eq, ink::storage::traits::StorageLayout)
+    )]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub struct MaskedPhone {
+        pub phone: String,
+        pub commitment: Hash,
+    }
+
+    impl MaskedPhone {
+        pub fn validate(&self) -> Result<()> {
+            if !is_masked_phone(&self.phone) {
+                return Err(MarketplaceError::PhoneNotMasked);
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Clone)]
+    #[cfg_attr(
+        feature = "std",
+        derive(Debug, PartialEq, Eq, ink::storage::traits::StorageLayout)
+    )]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub struct Listing {
+        id: u64,
+        store_id: u64,
+        seller_id: u64,
+        authority: AccountId,
+        title: String,
+        description: String,
+        price: Balance,
+        stock: u32,
+        images: Vec<String>,
+        category_id: Option<u64>,
+        payment_asset: PaymentAsset,
+        created_at: u64,
+        updated_at: u64,
+    }
+
+    #[derive(Clone)]
+    #[cfg_attr(
+        feature = "std",
+        derive(Debug, PartialEq, Eq, ink::storage::traits::StorageLayout)
+    )]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub struct Subscription {
+        store_id: u64,
+        seller_address: AccountId,
+        radius: u64,
+        category_ids: Vec<u64>,
+    }
+
+    #[derive(Clone)]
+    #[cfg_attr(
+        feature = "std",
+        derive(Debug, PartialEq, Eq, ink::storage::traits::StorageLayout)
+    )]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub struct Category {
+        id: u64,
+        name: String,
     }
 
     #[derive(Clone)]
@@ -65,6 +466,52 @@ mod marketplace {
         account_type: AccountType,
         authority: AccountId,
         location_enabled: bool,
+        reputation: Reputation,
+        contact_commitment: Hash,
+    }
+
+    // Each contact is encrypted off-chain to the counterparty's key
+    #[derive(Clone, Default)]
+    #[cfg_attr(
+        feature = "std",
+        derive(Debug, PartialEq, Eq, ink::storage::traits::StorageLayout)
+    )]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub struct ContactExchange {
+        buyer_key: Option<[u8; 32]>,
+        seller_key: Option<[u8; 32]>,
+        buyer_contact: Option<Vec<u8>>,
+        seller_contact: Option<Vec<u8>>,
+    }
+
+    #[derive(Clone, Default)]
+    #[cfg_attr(
+        feature = "std",
+        derive(Debug, PartialEq, Eq, ink::storage::traits::StorageLayout)
+    )]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub struct Reputation {
+        review_count: u64,
+        rating_sum: u64,
+        recent_ratings: Vec<u8>,
+        // Scaled by 100
+        recent_average: u32,
+    }
+
+    #[derive(Clone)]
+    #[cfg_attr(
+        feature = "std",
+        derive(Debug, PartialEq, Eq, ink::storage::traits::StorageLayout)
+    )]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub struct Review {
+        id: u64,
+        request_id: u64,
+        reviewer_id: u64,
+        reviewee_id: u64,
+        rating: u8,
+        comment_uri: String,
+        created_at: u64,
     }
 
     #[derive(Clone)]
@@ -77,7 +524,7 @@ mod marketplace {
         id: u64,
         name: String,
         buyer_id: u64,
-        sellers_price_quote: i64,
+        sellers_price_quote: Balance,
         seller_ids: Vec<u64>,
         offer_ids: Vec<u64>,
         locked_seller_id: u64,
@@ -87,6 +534,15 @@ mod marketplace {
         lifecycle: RequestLifecycle,
         location: Location,
         updated_at: u64,
+        escrowed_amount: Balance,
+        payment_asset: PaymentAsset,
+        expires_at: Option<u64>,
+        bidding_mode: BiddingMode,
+        category_id: Option<u64>,
+        tags: Vec<String>,
+        listing_id: Option<u64>,
+        listing_quantity: u32,
+        lock_at: u64,
     }
 
     #[derive(Clone)]
@@ -97,15 +553,34 @@ mod marketplace {
     #[ink::scale_derive(Encode, Decode, TypeInfo)]
     pub struct Offer {
         id: u64,
-        price: i64,
+        price: Balance,
         images: Vec<String>,
         request_id: u64,
+        store_id: u64,
         store_name: String,
+        store_location: Location,
         seller_id: u64,
         is_accepted: bool,
         created_at: u64,
         updated_at: u64,
         authority: AccountId,
+        payment_asset: PaymentAsset,
+        is_void: bool,
+        counter_price: Option<Balance>,
+        negotiation_rounds: u8,
+    }
+
+    #[derive(Clone, Copy, PartialEq)]
+    #[cfg_attr(
+        feature = "std",
+        derive(Debug, Eq, ink::storage::traits::StorageLayout)
+    )]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub enum CounterOfferResponse {
+        Accept,
+        Reject,
+        Counter(Balance),
     }
 
     #[ink(event)]
@@ -137,1234 +612,7939 @@ mod marketplace {
     }
 
     #[ink(event)]
-    pub struct RequestRemoved {
+    pub struct StoreUpdated {
         #[ink(topic)]
-        request_id: u64,
+        seller_address: AccountId,
+        store_id: u64,
+        store_name: String,
+        latitude: i128,
+        longitude: i128,
+        is_active: bool,
+    }
+
+    #[ink(event)]
+    pub struct StoreRemoved {
         #[ink(topic)]
-        buyer_address: AccountId,
+        seller_address: AccountId,
+        store_id: u64,
         removed_at: u64,
     }
 
     #[ink(event)]
-    pub struct OfferAccepted {
+    pub struct ListingCreated {
         #[ink(topic)]
-        offer_id: u64,
+        listing_id: u64,
         #[ink(topic)]
-        buyer_address: AccountId,
-        is_accepted: bool,
+        store_id: u64,
+        seller_address: AccountId,
+        title: String,
+        price: Balance,
+        stock: u32,
+        category_id: Option<u64>,
+        payment_asset: PaymentAsset,
     }
 
     #[ink(event)]
-    pub struct RequestCreated {
-        #[ink(topic)]
-        request_id: u64,
+    pub struct ListingUpdated {
         #[ink(topic)]
-        buyer_address: AccountId,
-        request_name: String,
-        latitude: i128,
-        longitude: i128,
-        images: Vec<String>,
-        lifecycle: u8,
-        description: String,
-        buyer_id: u64,
-        seller_ids: Vec<u64>,
-        sellers_price_quote: i64,
-        locked_seller_id: u64,
-        created_at: u64,
+        listing_id: u64,
+        title: String,
+        price: Balance,
+        stock: u32,
+        category_id: Option<u64>,
         updated_at: u64,
     }
+
     #[ink(event)]
-    pub struct LocationEnabled {
+    pub struct CategoryAdded {
         #[ink(topic)]
-        authority: AccountId,
-        location_enabled: bool,
-        user_id: u64,
-        created_at: u64,
-        updated_at: u64,
+        category_id: u64,
+        name: String,
     }
 
     #[ink(event)]
-    pub struct OfferCreated {
-        #[ink(topic)]
-        offer_id: u64,
+    pub struct RoleGranted {
         #[ink(topic)]
-        seller_address: AccountId,
-        store_name: String,
-        price: i64,
-        request_id: u64,
-        images: Vec<String>,
-        seller_id: u64,
-        seller_ids: Vec<u64>,
+        account: AccountId,
+        role: Role,
+        granted_by: AccountId,
     }
 
     #[ink(event)]
-    pub struct RequestAccepted {
-        #[ink(topic)]
-        request_id: u64,
+    pub struct RoleRevoked {
         #[ink(topic)]
-        offer_id: u64,
-        #[ink(topic)]
-        seller_id: u64,
-        updated_at: u64,
-        sellers_price_quote: i64,
+        account: AccountId,
+        role: Role,
+        revoked_by: AccountId,
     }
 
     #[ink(event)]
-    pub struct OfferRemoved {
-        #[ink(topic)]
-        offer_id: u64,
+    pub struct ConfigUpdated {
         #[ink(topic)]
-        seller_address: AccountId,
+        account: AccountId,
+        config: MarketplaceConfig,
     }
 
-    #[derive(Clone, PartialEq)]
-    #[cfg_attr(
-        feature = "std",
-        derive(Debug, Eq, ink::storage::traits::StorageLayout)
-    )]
-    #[ink::scale_derive(Encode, Decode, TypeInfo)]
-    pub enum AccountType {
-        Buyer,
-        Seller,
+    #[ink(event)]
+    pub struct Paused {
+        #[ink(topic)]
+        account: AccountId,
     }
 
-    impl Default for AccountType {
-        fn default() -> Self {
-            AccountType::Buyer
-        }
+    #[ink(event)]
+    pub struct Unpaused {
+        #[ink(topic)]
+        account: AccountId,
     }
 
-    #[derive(Clone, PartialEq)]
-    #[cfg_attr(
-        feature = "std",
-        derive(Debug, Eq, ink::storage::traits::StorageLayout)
-    )]
-    #[ink::scale_derive(Encode, Decode, TypeInfo)]
-    pub enum RequestLifecycle {
-        Pending,
-        AcceptedBySeller,
-        AcceptedByBuyer,
-        RequestLocked,
-        Completed,
+    #[ink(event)]
+    pub struct CircuitBreakerChanged {
+        #[ink(topic)]
+        account: AccountId,
+        operation: Operation,
+        paused: bool,
     }
 
-    impl Default for RequestLifecycle {
-        fn default() -> Self {
-            RequestLifecycle::Pending
-        }
+    #[ink(event)]
+    pub struct OwnershipTransferred {
+        #[ink(topic)]
+        previous_owner: AccountId,
+        #[ink(topic)]
+        new_owner: AccountId,
     }
 
-    #[ink(storage)]
-    pub struct Marketplace {
-        users: Mapping<AccountId, User>,
-        requests: Mapping<u64, Request>,
-        offers: Mapping<u64, Offer>,
-        user_store_ids: Mapping<AccountId, Vec<u64>>,
-        user_stores: Mapping<(AccountId, u64), Store>,
-        user_counter: u64,
-        store_counter: u64,
-        request_counter: u64,
-        offer_counter: u64,
-        TIME_TO_LOCK: u64,
-        user_ids: Mapping<u64, AccountId>,
+    #[ink(event)]
+    pub struct CategoryRemoved {
+        #[ink(topic)]
+        category_id: u64,
+    }
+
+    #[ink(event)]
+    pub struct SubscriptionUpdated {
+        #[ink(topic)]
+        seller_address: AccountId,
+        store_id: u64,
+        radius: u64,
+        category_ids: Vec<u64>,
+    }
+
+    #[ink(event)]
+    pub struct SubscriptionRemoved {
+        #[ink(topic)]
+        seller_address: AccountId,
+        store_id: u64,
+    }
+
+    #[ink(event)]
+    pub struct ContactKeyPublished {
+        #[ink(topic)]
+        request_id: u64,
+        #[ink(topic)]
+        user_address: AccountId,
+        public_key: [u8; 32],
+    }
+
+    #[ink(event)]
+    pub struct ContactShared {
+        #[ink(topic)]
+        request_id: u64,
+        #[ink(topic)]
+        user_address: AccountId,
+    }
+
+    #[ink(event)]
+    pub struct RequestMatched {
+        #[ink(topic)]
+        request_id: u64,
+        #[ink(topic)]
+        seller_address: AccountId,
+        store_id: u64,
+        distance: u64,
+    }
+
+    #[ink(event)]
+    pub struct ListingRemoved {
+        #[ink(topic)]
+        listing_id: u64,
+        #[ink(topic)]
+        store_id: u64,
+        removed_at: u64,
+    }
+
+    #[ink(event)]
+    pub struct ListingPurchased {
+        #[ink(topic)]
+        listing_id: u64,
+        #[ink(topic)]
+        request_id: u64,
+        buyer_address: AccountId,
+        quantity: u32,
+        total_price: Balance,
+        remaining_stock: u32,
+    }
+
+    #[ink(event)]
+    pub struct RequestRemoved {
+        #[ink(topic)]
+        request_id: u64,
+        #[ink(topic)]
+        buyer_address: AccountId,
+        removed_at: u64,
+    }
+
+    #[ink(event)]
+    pub struct OfferAccepted {
+        #[ink(topic)]
+        offer_id: u64,
+        #[ink(topic)]
+        buyer_address: AccountId,
+        is_accepted: bool,
+    }
+
+    #[ink(event)]
+    pub struct RequestCreated {
+        #[ink(topic)]
+        request_id: u64,
+        #[ink(topic)]
+        buyer_address: AccountId,
+        request_name: String,
+        latitude: i128,
+        longitude: i128,
+        images: Vec<String>,
+        lifecycle: u8,
+        description: String,
+        buyer_id: u64,
+        seller_ids: Vec<u64>,
+        sellers_price_quote: Balance,
+        locked_seller_id: u64,
+        created_at: u64,
+        updated_at: u64,
+        payment_asset: PaymentAsset,
+        expires_at: Option<u64>,
+        bidding_mode: BiddingMode,
+        category_id: Option<u64>,
+        tags: Vec<String>,
+    }
+    #[ink(event)]
+    pub struct LocationEnabled {
+        #[ink(topic)]
+        authority: AccountId,
+        location_enabled: bool,
+        user_id: u64,
+        created_at: u64,
+        updated_at: u64,
+    }
+
+    #[ink(event)]
+    pub struct OfferCreated {
+        #[ink(topic)]
+        offer_id: u64,
+        #[ink(topic)]
+        seller_address: AccountId,
+        store_id: u64,
+        store_name: String,
+        price: Balance,
+        request_id: u64,
+        images: Vec<String>,
+        seller_id: u64,
+        seller_ids: Vec<u64>,
+        payment_asset: PaymentAsset,
+    }
+
+    #[ink(event)]
+    pub struct RequestAccepted {
+        #[ink(topic)]
+        request_id: u64,
+        #[ink(topic)]
+        offer_id: u64,
+        #[ink(topic)]
+        seller_id: u64,
+        updated_at: u64,
+        sellers_price_quote: Balance,
+    }
+
+    #[ink(event)]
+    pub struct EscrowReleased {
+        #[ink(topic)]
+        request_id: u64,
+        #[ink(topic)]
+        seller_address: AccountId,
+        amount: Balance,
+    }
+
+    #[ink(event)]
+    pub struct EscrowRefunded {
+        #[ink(topic)]
+        request_id: u64,
+        #[ink(topic)]
+        buyer_address: AccountId,
+        amount: Balance,
+    }
+
+    #[ink(event)]
+    pub struct DisputeOpened {
+        #[ink(topic)]
+        request_id: u64,
+        #[ink(topic)]
+        opened_by: AccountId,
+        evidence_uri: String,
+    }
+
+    #[ink(event)]
+    pub struct DisputeEvidenceAdded {
+        #[ink(topic)]
+        request_id: u64,
+        #[ink(topic)]
+        submitted_by: AccountId,
+        evidence_uri: String,
+    }
+
+    #[ink(event)]
+    pub struct DisputeVoteCast {
+        #[ink(topic)]
+        request_id: u64,
+        #[ink(topic)]
+        arbitrator: AccountId,
+        ruling: DisputeRuling,
+    }
+
+    #[ink(event)]
+    pub struct DisputeRuled {
+        #[ink(topic)]
+        request_id: u64,
+        ruling: DisputeRuling,
+        resolved_at: u64,
+    }
+
+    #[ink(event)]
+    pub struct ReviewSubmitted {
+        #[ink(topic)]
+        request_id: u64,
+        #[ink(topic)]
+        reviewee_id: u64,
+        review_id: u64,
+        reviewer_id: u64,
+        rating: u8,
+    }
+
+    #[ink(event)]
+    pub struct RequestCancelled {
+        #[ink(topic)]
+        request_id: u64,
+        #[ink(topic)]
+        buyer_address: AccountId,
+        cancellation_fee: Balance,
+        refunded: Balance,
+        cancelled_at: u64,
+    }
+
+    #[ink(event)]
+    pub struct RequestLifecycleChanged {
+        #[ink(topic)]
+        request_id: u64,
+        from: RequestLifecycle,
+        to: RequestLifecycle,
+        updated_at: u64,
+    }
+
+    #[ink(event)]
+    pub struct OfferRemoved {
+        #[ink(topic)]
+        offer_id: u64,
+        #[ink(topic)]
+        seller_address: AccountId,
+    }
+
+    #[ink(event)]
+    pub struct OfferCountered {
+        #[ink(topic)]
+        offer_id: u64,
+        #[ink(topic)]
+        request_id: u64,
+        counter_price: Balance,
+        negotiation_rounds: u8,
+    }
+
+    #[ink(event)]
+    pub struct CounterOfferResponded {
+        #[ink(topic)]
+        offer_id: u64,
+        #[ink(topic)]
+        request_id: u64,
+        response: CounterOfferResponse,
+        price: Balance,
+        negotiation_rounds: u8,
+    }
+
+    #[ink(event)]
+    pub struct OfferCommitted {
+        #[ink(topic)]
+        request_id: u64,
+        #[ink(topic)]
+        seller_id: u64,
+        commitment: Hash,
+    }
+
+    #[ink(event)]
+    pub struct OfferRevealed {
+        #[ink(topic)]
+        request_id: u64,
+        #[ink(topic)]
+        seller_id: u64,
+        offer_id: u64,
+        price: Balance,
+    }
+
+    #[ink(event)]
+    pub struct CommitmentsDiscarded {
+        #[ink(topic)]
+        request_id: u64,
+        seller_ids: Vec<u64>,
+    }
+
+    #[ink(event)]
+    pub struct AuctionSettled {
+        #[ink(topic)]
+        request_id: u64,
+        winning_offer_id: Option<u64>,
+        price: Balance,
+        settled_at: u64,
+    }
+
+    #[ink(event)]
+    pub struct OfferUpdated {
+        #[ink(topic)]
+        offer_id: u64,
+        #[ink(topic)]
+        seller_address: AccountId,
+        price: Balance,
+        images: Vec<String>,
+        updated_at: u64,
+    }
+
+    #[derive(Clone, PartialEq, Default)]
+    #[cfg_attr(
+        feature = "std",
+        derive(Debug, Eq, ink::storage::traits::StorageLayout)
+    )]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub enum AccountType {
+        #[default]
+        Buyer,
+        Seller,
     }
 
-    impl Marketplace {
-        #[ink(constructor)]
-        pub fn new() -> Self {
-            Self {
-                users: Mapping::default(),
-                requests: Mapping::default(),
-                offers: Mapping::default(),
-                user_store_ids: Mapping::default(),
-                user_stores: Mapping::default(),
-                user_counter: 0,
-                store_counter: 0,
-                request_counter: 0,
-                offer_counter: 0,
-                TIME_TO_LOCK: 900 * 1000,
-                user_ids: Mapping::default(),
-            }
+    // The owner and admins hold every role except `Arbitrator`
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub enum Role {
+        Admin,
+        Moderator,
+        Arbitrator,
+        FeeManager,
+    }
+
+    #[derive(Clone)]
+    #[cfg_attr(
+        feature = "std",
+        derive(Debug, PartialEq, Eq, ink::storage::traits::StorageLayout)
+    )]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub struct MarketplaceConfig {
+        // In milliseconds
+        pub time_to_lock: u64,
+        pub max_images_per_request: u32,
+        pub max_offers_per_request: u32,
+        // In basis points
+        pub cancellation_fee_bps: u16,
+    }
+
+    impl Default for MarketplaceConfig {
+        fn default() -> Self {
+            Self {
+                time_to_lock: DEFAULT_TIME_TO_LOCK,
+                max_images_per_request: 10,
+                max_offers_per_request: 50,
+                cancellation_fee_bps: 0,
+            }
+        }
+    }
+
+    impl MarketplaceConfig {
+        fn validate(&self) -> Result<()> {
+            if self.cancellation_fee_bps > BASIS_POINTS {
+                return Err(MarketplaceError::InvalidFee);
+            }
+            if self.max_offers_per_request == 0
+                || !(1..=MAX_IMAGES_PER_REQUEST).contains(&self.max_images_per_request)
+                || !(MIN_TIME_TO_LOCK..=MAX_TIME_TO_LOCK).contains(&self.time_to_lock)
+            {
+                return Err(MarketplaceError::InvalidConfig);
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub enum Operation {
+        Requests,
+        Offers,
+        Acceptances,
+    }
+
+    #[derive(Clone, Default)]
+    #[cfg_attr(
+        feature = "std",
+        derive(Debug, PartialEq, Eq, ink::storage::traits::StorageLayout)
+    )]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub struct CircuitBreakers {
+        requests: bool,
+        offers: bool,
+        acceptances: bool,
+    }
+
+    impl CircuitBreakers {
+        fn get_mut(&mut self, operation: Operation) -> &mut bool {
+            match operation {
+                Operation::Requests => &mut self.requests,
+                Operation::Offers => &mut self.offers,
+                Operation::Acceptances => &mut self.acceptances,
+            }
+        }
+
+        fn is_tripped(&self, operation: Operation) -> bool {
+            match operation {
+                Operation::Requests => self.requests,
+                Operation::Offers => self.offers,
+                Operation::Acceptances => self.acceptances,
+            }
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub enum RequestLifecycle {
+        #[default]
+        Pending,
+        AcceptedBySeller,
+        AcceptedByBuyer,
+        RequestLocked,
+        Completed,
+        Disputed,
+        Resolved,
+        Cancelled,
+    }
+
+    impl RequestLifecycle {
+        // Revoking an acceptance goes through `can_revoke_acceptance` instead
+        pub fn can_transition_to(&self, to: RequestLifecycle) -> bool {
+            use RequestLifecycle::*;
+            matches!(
+                (*self, to),
+                (Pending, AcceptedBySeller)
+                    | (AcceptedBySeller, Pending)
+                    | (AcceptedBySeller, AcceptedBySeller)
+                    | (AcceptedBySeller, AcceptedByBuyer)
+                    | (AcceptedByBuyer, AcceptedByBuyer)
+                    | (AcceptedByBuyer, RequestLocked)
+                    | (RequestLocked, Completed)
+                    | (AcceptedByBuyer, Disputed)
+                    | (RequestLocked, Disputed)
+                    | (Disputed, Resolved)
+                    | (Pending, Cancelled)
+                    | (AcceptedBySeller, Cancelled)
+                    | (AcceptedByBuyer, Cancelled)
+            )
+        }
+
+        pub fn takes_offers(&self) -> bool {
+            matches!(
+                self,
+                RequestLifecycle::Pending | RequestLifecycle::AcceptedBySeller
+            )
+        }
+
+        pub fn is_matched(&self) -> bool {
+            matches!(
+                self,
+                RequestLifecycle::AcceptedByBuyer
+                    | RequestLifecycle::RequestLocked
+                    | RequestLifecycle::Disputed
+            )
+        }
+
+        pub fn can_revoke_acceptance(&self) -> bool {
+            *self == RequestLifecycle::AcceptedByBuyer
+        }
+    }
+
+    #[derive(Clone, Copy, PartialEq)]
+    #[cfg_attr(
+        feature = "std",
+        derive(Debug, Eq, ink::storage::traits::StorageLayout)
+    )]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub enum DisputeRuling {
+        ReleaseToSeller,
+        RefundBuyer,
+    }
+
+    #[derive(Clone)]
+    #[cfg_attr(
+        feature = "std",
+        derive(Debug, PartialEq, Eq, ink::storage::traits::StorageLayout)
+    )]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub struct Evidence {
+        submitted_by: AccountId,
+        uri: String,
+        submitted_at: u64,
+    }
+
+    #[derive(Clone)]
+    #[cfg_attr(
+        feature = "std",
+        derive(Debug, PartialEq, Eq, ink::storage::traits::StorageLayout)
+    )]
+    #[ink::scale_derive(Encode, Decode, TypeInfo)]
+    pub struct Dispute {
+        request_id: u64,
+        opened_by: AccountId,
+        evidence: Vec<Evidence>,
+        voters: Vec<AccountId>,
+        votes_to_release: u32,
+        votes_to_refund: u32,
+        // Trimmed to an odd size, without the buyer and the locked seller
+        panel: Vec<AccountId>,
+        ruling: Option<DisputeRuling>,
+        created_at: u64,
+        updated_at: u64,
+    }
+
+    #[ink(storage)]
+    pub struct Marketplace {
+        users: Mapping<AccountId, User>,
+        requests: Mapping<u64, Request>,
+        offers: Mapping<u64, Offer>,
+        user_store_ids: Mapping<AccountId, Vec<u64>>,
+        user_stores: Mapping<(AccountId, u64), Store>,
+        user_counter: u64,
+        store_counter: u64,
+        request_counter: u64,
+        offer_counter: u64,
+        config: MarketplaceConfig,
+        user_ids: Mapping<u64, AccountId>,
+        owner: AccountId,
+        roles: Mapping<(AccountId, Role), ()>,
+        arbitrators: Vec<AccountId>,
+        paused: bool,
+        circuit_breakers: CircuitBreakers,
+        disputes: Mapping<u64, Dispute>,
+        reviews: Mapping<u64, Review>,
+        review_counter: u64,
+        user_review_ids: Mapping<u64, Vec<u64>>,
+        request_reviews: Mapping<(u64, u64), u64>,
+        seller_offer_ids: Mapping<(u64, u64), u64>,
+        offer_commitments: Mapping<(u64, u64), Hash>,
+        request_commitments: Mapping<u64, Vec<u64>>,
+        store_owners: Mapping<u64, AccountId>,
+        listings: Mapping<u64, Listing>,
+        listing_counter: u64,
+        store_listing_ids: Mapping<u64, Vec<u64>>,
+        categories: Mapping<u64, Category>,
+        category_counter: u64,
+        category_request_ids: Mapping<(u64, u64), u64>,
+        category_request_count: Mapping<u64, u64>,
+        store_subscriptions: Mapping<u64, Subscription>,
+        category_subscribers: Mapping<(u64, u64), u64>,
+        category_subscriber_count: Mapping<u64, u64>,
+        category_subscriber_positions: Mapping<(u64, u64), u64>,
+        matched_request_ids: Mapping<AccountId, Vec<u64>>,
+        cell_request_ids: Mapping<(u64, u64), u64>,
+        cell_request_count: Mapping<u64, u64>,
+        cell_request_positions: Mapping<(u64, u64), u64>,
+        cell_store_ids: Mapping<(u64, u64), u64>,
+        cell_store_count: Mapping<u64, u64>,
+        cell_store_positions: Mapping<(u64, u64), u64>,
+        matched_users: Mapping<(u64, u64), u64>,
+        contact_exchanges: Mapping<(u64, u64), ContactExchange>,
+    }
+
+    impl Marketplace {
+        #[ink(constructor)]
+        pub fn new(config: MarketplaceConfig) -> Result<Self> {
+            config.validate()?;
+            Ok(Self {
+                users: Mapping::default(),
+                requests: Mapping::default(),
+                offers: Mapping::default(),
+                user_store_ids: Mapping::default(),
+                user_stores: Mapping::default(),
+                user_counter: 0,
+                store_counter: 0,
+                request_counter: 0,
+                offer_counter: 0,
+                config,
+                user_ids: Mapping::default(),
+                owner: Self::env().caller(),
+                roles: Mapping::default(),
+                arbitrators: Vec::new(),
+                paused: false,
+                circuit_breakers: CircuitBreakers::default(),
+                disputes: Mapping::default(),
+                reviews: Mapping::default(),
+                review_counter: 0,
+                user_review_ids: Mapping::default(),
+                request_reviews: Mapping::default(),
+                seller_offer_ids: Mapping::default(),
+                offer_commitments: Mapping::default(),
+                request_commitments: Mapping::default(),
+                store_owners: Mapping::default(),
+                listings: Mapping::default(),
+                listing_counter: 0,
+                store_listing_ids: Mapping::default(),
+                categories: Mapping::default(),
+                category_counter: 0,
+                category_request_ids: Mapping::default(),
+                category_request_count: Mapping::default(),
+                store_subscriptions: Mapping::default(),
+                category_subscribers: Mapping::default(),
+                category_subscriber_count: Mapping::default(),
+                category_subscriber_positions: Mapping::default(),
+                matched_request_ids: Mapping::default(),
+                cell_request_ids: Mapping::default(),
+                cell_request_count: Mapping::default(),
+                cell_request_positions: Mapping::default(),
+                cell_store_ids: Mapping::default(),
+                cell_store_count: Mapping::default(),
+                cell_store_positions: Mapping::default(),
+                matched_users: Mapping::default(),
+                contact_exchanges: Mapping::default(),
+            })
+        }
+
+        #[ink(message)]
+        pub fn create_user(
+            &mut self,
+            username: String,
+            phone: MaskedPhone,
+            latitude: i128,
+            longitude: i128,
+            account_type: AccountType,
+        ) -> Result<()> {
+            self.ensure_not_paused()?;
+            let caller = self.env().caller();
+            let user = self.users.get(caller);
+            if user.is_some() {
+                return Err(MarketplaceError::UserAlreadyExists);
+            }
+            phone.validate()?;
+            let location = Location::new(latitude, longitude)?;
+            self.user_counter = self.user_counter.checked_add(1).unwrap();
+            let new_user = User {
+                id: self.user_counter,
+                username: username.clone(),
+                phone: phone.phone,
+                location,
+                created_at: self.env().block_timestamp(),
+                updated_at: self.env().block_timestamp(),
+                account_type: account_type.clone(),
+                authority: caller,
+                location_enabled: true, // NOTE: we enable location by default
+                reputation: Reputation::default(),
+                contact_commitment: phone.commitment,
+            };
+
+            self.users.insert(caller, &new_user);
+            self.user_ids.insert(self.user_counter, &caller);
+            self.env().emit_event(UserCreated {
+                user_address: caller,
+                user_id: self.user_counter,
+                username,
+                account_type: match account_type {
+                    AccountType::Buyer => 0,
+                    AccountType::Seller => 1,
+                },
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn update_user(
+            &mut self,
+            username: String,
+            phone: MaskedPhone,
+            latitude: i128,
+            longitude: i128,
+            account_type: AccountType,
+        ) -> Result<()> {
+            self.ensure_not_paused()?;
+            let caller = self.env().caller();
+            let mut user = self
+                .users
+                .get(caller)
+                .ok_or(MarketplaceError::InvalidUser)?;
+            phone.validate()?;
+
+            user.username = username.clone();
+            user.phone = phone.phone;
+            user.contact_commitment = phone.commitment;
+            user.location = Location::new(latitude, longitude)?;
+            user.updated_at = self.env().block_timestamp();
+            user.account_type = account_type.clone();
+
+            self.users.insert(caller, &user);
+
+            self.env().emit_event(UserUpdated {
+                user_address: caller,
+                user_id: user.id,
+                username,
+                account_type: match account_type {
+                    AccountType::Buyer => 0,
+                    AccountType::Seller => 1,
+                },
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn create_store(
+            &mut self,
+            name: String,
+            description: String,
+            phone: MaskedPhone,
+            latitude: i128,
+            longitude: i128,
+        ) -> Result<()> {
+            self.ensure_not_paused()?;
+            let caller = self.env().caller();
+            let user = self
+                .users
+                .get(caller)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            if user.account_type != AccountType::Seller {
+                return Err(MarketplaceError::OnlySellersAllowed);
+            }
+
+            phone.validate()?;
+            let location = Location::new(latitude, longitude)?;
+            self.store_counter = self.store_counter.checked_add(1).unwrap();
+            let new_store = Store {
+                id: self.store_counter,
+                name: name.clone(),
+                description,
+                phone: phone.phone,
+                phone_commitment: phone.commitment,
+                location,
+                is_active: true,
+            };
+
+            self.user_stores
+                .insert((caller, self.store_counter), &new_store);
+            let mut store_ids = self.user_store_ids.get(caller).unwrap_or_default();
+            store_ids.push(self.store_counter);
+            self.user_store_ids.insert(caller, &store_ids);
+            self.store_owners.insert(self.store_counter, &caller);
+            self.index_store(new_store.location.cell(), self.store_counter);
+
+            self.env().emit_event(StoreCreated {
+                seller_address: caller,
+                store_id: self.store_counter,
+                store_name: name,
+                latitude,
+                longitude,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn update_store(
+            &mut self,
+            store_id: u64,
+            name: String,
+            description: String,
+            phone: MaskedPhone,
+            latitude: i128,
+            longitude: i128,
+        ) -> Result<()> {
+            self.ensure_not_paused()?;
+            let caller = self.env().caller();
+            let mut store = self
+                .user_stores
+                .get((caller, store_id))
+                .ok_or(MarketplaceError::StoreNotFound)?;
+            phone.validate()?;
+
+            let location = Location::new(latitude, longitude)?;
+            if location.cell() != store.location.cell() {
+                self.unindex_store(store.location.cell(), store_id);
+                self.index_store(location.cell(), store_id);
+            }
+
+            store.name = name.clone();
+            store.description = description;
+            store.phone = phone.phone;
+            store.phone_commitment = phone.commitment;
+            store.location = location;
+            self.user_stores.insert((caller, store_id), &store);
+
+            self.env().emit_event(StoreUpdated {
+                seller_address: caller,
+                store_id,
+                store_name: name,
+                latitude,
+                longitude,
+                is_active: store.is_active,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn deactivate_store(&mut self, store_id: u64) -> Result<()> {
+            self.ensure_not_paused()?;
+            self.set_store_active(store_id, false)
+        }
+
+        #[ink(message)]
+        pub fn activate_store(&mut self, store_id: u64) -> Result<()> {
+            self.ensure_not_paused()?;
+            self.set_store_active(store_id, true)
+        }
+
+        #[ink(message)]
+        pub fn delete_store(&mut self, store_id: u64) -> Result<()> {
+            self.ensure_not_paused()?;
+            let caller = self.env().caller();
+            let store = self
+                .user_stores
+                .take((caller, store_id))
+                .ok_or(MarketplaceError::StoreNotFound)?;
+            self.unindex_store(store.location.cell(), store_id);
+
+            let mut store_ids = self.user_store_ids.get(caller).unwrap_or_default();
+            store_ids.retain(|id| *id != store_id);
+            self.user_store_ids.insert(caller, &store_ids);
+            self.store_owners.remove(store_id);
+
+            self.remove_subscription(store_id);
+
+            // A store's catalog goes with it
+            for listing_id in self
+                .store_listing_ids
+                .take(store_id)
+                .unwrap_or_default()
+                .iter()
+            {
+                self.listings.remove(*listing_id);
+            }
+
+            self.env().emit_event(StoreRemoved {
+                seller_address: caller,
+                store_id,
+                removed_at: self.env().block_timestamp(),
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        #[allow(clippy::too_many_arguments)]
+        pub fn create_listing(
+            &mut self,
+            store_id: u64,
+            title: String,
+            description: String,
+            price: Balance,
+            stock: u32,
+            images: Vec<String>,
+            category_id: Option<u64>,
+            payment_asset: PaymentAsset,
+        ) -> Result<()> {
+            self.ensure_not_paused()?;
+            let caller = self.env().caller();
+            let user = self
+                .users
+                .get(caller)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            if user.account_type != AccountType::Seller {
+                return Err(MarketplaceError::OnlySellersAllowed);
+            }
+
+            let store = self
+                .user_stores
+                .get((caller, store_id))
+                .ok_or(MarketplaceError::StoreNotFound)?;
+            if !store.is_active {
+                return Err(MarketplaceError::StoreInactive);
+            }
+
+            self.ensure_category(category_id)?;
+
+            self.listing_counter = self.listing_counter.checked_add(1).unwrap();
+            let listing = Listing {
+                id: self.listing_counter,
+                store_id,
+                seller_id: user.id,
+                authority: caller,
+                title: title.clone(),
+                description,
+                price,
+                stock,
+                images,
+                category_id,
+                payment_asset,
+                created_at: self.env().block_timestamp(),
+                updated_at: self.env().block_timestamp(),
+            };
+            self.listings.insert(self.listing_counter, &listing);
+            let mut listing_ids = self.store_listing_ids.get(store_id).unwrap_or_default();
+            listing_ids.push(self.listing_counter);
+            self.store_listing_ids.insert(store_id, &listing_ids);
+
+            self.env().emit_event(ListingCreated {
+                listing_id: self.listing_counter,
+                store_id,
+                seller_address: caller,
+                title,
+                price,
+                stock,
+                category_id,
+                payment_asset,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        #[allow(clippy::too_many_arguments)]
+        pub fn update_listing(
+            &mut self,
+            listing_id: u64,
+            title: String,
+            description: String,
+            price: Balance,
+            stock: u32,
+            images: Vec<String>,
+            category_id: Option<u64>,
+        ) -> Result<()> {
+            self.ensure_not_paused()?;
+            let caller = self.env().caller();
+            let mut listing = self
+                .listings
+                .get(listing_id)
+                .ok_or(MarketplaceError::ListingNotFound)?;
+
+            if listing.authority != caller {
+                return Err(MarketplaceError::UnauthorizedSeller);
+            }
+
+            self.ensure_category(category_id)?;
+
+            listing.title = title.clone();
+            listing.description = description;
+            listing.price = price;
+            listing.stock = stock;
+            listing.images = images;
+            listing.category_id = category_id;
+            listing.updated_at = self.env().block_timestamp();
+            self.listings.insert(listing_id, &listing);
+
+            self.env().emit_event(ListingUpdated {
+                listing_id,
+                title,
+                price,
+                stock,
+                category_id,
+                updated_at: listing.updated_at,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn remove_listing(&mut self, listing_id: u64) -> Result<()> {
+            self.ensure_not_paused()?;
+            let caller = self.env().caller();
+            let listing = self
+                .listings
+                .get(listing_id)
+                .ok_or(MarketplaceError::ListingNotFound)?;
+
+            if listing.authority != caller {
+                return Err(MarketplaceError::UnauthorizedSeller);
+            }
+
+            self.listings.remove(listing_id);
+            let mut listing_ids = self
+                .store_listing_ids
+                .get(listing.store_id)
+                .unwrap_or_default();
+            listing_ids.retain(|id| *id != listing_id);
+            self.store_listing_ids
+                .insert(listing.store_id, &listing_ids);
+
+            self.env().emit_event(ListingRemoved {
+                listing_id,
+                store_id: listing.store_id,
+                removed_at: self.env().block_timestamp(),
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn subscribe(
+            &mut self,
+            store_id: u64,
+            radius: u64,
+            category_ids: Vec<u64>,
+        ) -> Result<()> {
+            self.ensure_not_paused()?;
+            let caller = self.env().caller();
+            if !self.user_stores.contains((caller, store_id)) {
+                return Err(MarketplaceError::StoreNotFound);
+            }
+
+            if category_ids.is_empty() || category_ids.len() > MAX_SUBSCRIPTION_CATEGORIES {
+                return Err(MarketplaceError::InvalidSubscription);
+            }
+            for (index, category_id) in category_ids.iter().enumerate() {
+                self.ensure_category(Some(*category_id))?;
+                if category_ids[..index].contains(category_id) {
+                    return Err(MarketplaceError::InvalidSubscription);
+                }
+            }
+
+            self.remove_subscription(store_id);
+            for category_id in category_ids.iter() {
+                Self::push_id(
+                    &mut self.category_subscribers,
+                    &mut self.category_subscriber_count,
+                    &mut self.category_subscriber_positions,
+                    *category_id,
+                    store_id,
+                );
+            }
+            let subscription = Subscription {
+                store_id,
+                seller_address: caller,
+                radius,
+                category_ids: category_ids.clone(),
+            };
+            self.store_subscriptions.insert(store_id, &subscription);
+
+            self.env().emit_event(SubscriptionUpdated {
+                seller_address: caller,
+                store_id,
+                radius,
+                category_ids,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn unsubscribe(&mut self, store_id: u64) -> Result<()> {
+            self.ensure_not_paused()?;
+            let caller = self.env().caller();
+            if !self.user_stores.contains((caller, store_id)) {
+                return Err(MarketplaceError::StoreNotFound);
+            }
+            if !self.remove_subscription(store_id) {
+                return Err(MarketplaceError::SubscriptionNotFound);
+            }
+            Ok(())
+        }
+
+        #[ink(message, payable)]
+        pub fn purchase_listing(&mut self, listing_id: u64, quantity: u32) -> Result<()> {
+            self.ensure_operation_open(Operation::Requests)?;
+            self.ensure_operation_open(Operation::Acceptances)?;
+            let caller = self.env().caller();
+            let buyer = self
+                .users
+                .get(caller)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            if buyer.account_type != AccountType::Buyer {
+                return Err(MarketplaceError::OnlyBuyersAllowed);
+            }
+
+            let mut listing = self
+                .listings
+                .get(listing_id)
+                .ok_or(MarketplaceError::ListingNotFound)?;
+
+            if quantity == 0 {
+                return Err(MarketplaceError::InvalidQuantity);
+            }
+            if listing.stock < quantity {
+                return Err(MarketplaceError::OutOfStock);
+            }
+
+            let store = self
+                .user_stores
+                .get((listing.authority, listing.store_id))
+                .ok_or(MarketplaceError::StoreNotFound)?;
+            if !store.is_active {
+                return Err(MarketplaceError::StoreInactive);
+            }
+
+            let total_price = listing.price.checked_mul(quantity as Balance).unwrap();
+            self.collect_deposit(listing.payment_asset, caller, total_price)?;
+
+            listing.stock = listing.stock.checked_sub(quantity).unwrap();
+            listing.updated_at = self.env().block_timestamp();
+            self.listings.insert(listing_id, &listing);
+
+            let mut request = self.insert_request(
+                &buyer,
+                listing.title.clone(),
+                listing.description.clone(),
+                listing.images.clone(),
+                buyer.location.clone(),
+                RequestParams {
+                    payment_asset: listing.payment_asset,
+                    category_id: listing.category_id,
+                    ..Default::default()
+                },
+            );
+            request.listing_id = Some(listing_id);
+            request.listing_quantity = quantity;
+            self.transition(&mut request, RequestLifecycle::AcceptedBySeller)?;
+            let mut offer = self.insert_offer(
+                listing.authority,
+                listing.seller_id,
+                &mut request,
+                total_price,
+                listing.images,
+                store,
+            );
+            self.transition(&mut request, RequestLifecycle::AcceptedByBuyer)?;
+            self.accept(&mut request, &mut offer, caller);
+
+            self.env().emit_event(ListingPurchased {
+                listing_id,
+                request_id: request.id,
+                buyer_address: caller,
+                quantity,
+                total_price,
+                remaining_stock: listing.stock,
+            });
+            Ok(())
+        }
+
+        #[ink(message, payable)]
+        pub fn create_request(
+            &mut self,
+            name: String,
+            description: String,
+            images: Vec<String>,
+            latitude: i128,
+            longitude: i128,
+            params: RequestParams,
+        ) -> Result<()> {
+            self.ensure_operation_open(Operation::Requests)?;
+            let caller = self.env().caller();
+            let user = self
+                .users
+                .get(caller)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            if user.account_type != AccountType::Buyer {
+                return Err(MarketplaceError::OnlyBuyersAllowed);
+            }
+
+            let location = Location::new(latitude, longitude)?;
+            if images.len() > self.config.max_images_per_request as usize {
+                return Err(MarketplaceError::TooManyImages);
+            }
+            self.ensure_category(params.category_id)?;
+            if params.tags.len() > MAX_TAGS
+                || params
+                    .tags
+                    .iter()
+                    .any(|tag| tag.is_empty() || tag.len() > MAX_TAG_LENGTH)
+            {
+                return Err(MarketplaceError::InvalidTags);
+            }
+
+            if let Some(expires_at) = params.expires_at {
+                if expires_at <= self.env().block_timestamp() {
+                    return Err(MarketplaceError::InvalidExpiry);
+                }
+            }
+
+            if let BiddingMode::Sealed {
+                commit_ends_at,
+                reveal_ends_at,
+            } = params.bidding_mode
+            {
+                if commit_ends_at <= self.env().block_timestamp()
+                    || reveal_ends_at <= commit_ends_at
+                {
+                    return Err(MarketplaceError::InvalidBiddingWindow);
+                }
+            }
+
+            if let BiddingMode::ReverseAuction {
+                closes_at,
+                reserve_price,
+            } = params.bidding_mode
+            {
+                if closes_at <= self.env().block_timestamp() {
+                    return Err(MarketplaceError::InvalidBiddingWindow);
+                }
+                if reserve_price == 0 {
+                    return Err(MarketplaceError::InvalidReservePrice);
+                }
+                self.collect_deposit(params.payment_asset, caller, reserve_price)?;
+            } else if self.env().transferred_value() != 0 {
+                return Err(MarketplaceError::IncorrectDeposit);
+            }
+
+            let request = self.insert_request(&user, name, description, images, location, params);
+            self.notify_subscribers(&request, &user);
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn delete_request(&mut self, request_id: u64) -> Result<()> {
+            // Open while paused, see `pause`
+            let caller = self.env().caller();
+
+            // Fetch the request, or return an error if it doesn't exist
+            let mut request = self
+                .requests
+                .get(request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            // Check if the caller is the buyer who created the request
+            let user = self
+                .users
+                .get(caller)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            if request.buyer_id != user.id {
+                return Err(MarketplaceError::UnauthorizedBuyer);
+            }
+
+            // Only requests without an accepted offer can be removed; accepted ones
+            // go through `cancel_request`
+            self.lock_if_due(&mut request)?;
+            if request.lifecycle == RequestLifecycle::AcceptedByBuyer {
+                return Err(MarketplaceError::OfferAlreadyAccepted);
+            }
+            self.ensure_auction_not_closed(&request)?;
+
+            // Refund any auction reserve and void the offers before removing the
+            // request
+            self.cancel(&mut request, caller)?;
+
+            // Remove the request and its offers from storage
+            self.remove_request(&request, caller);
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn create_offer(
+            &mut self,
+            request_id: u64,
+            price: Balance,
+            images: Vec<String>,
+            store_id: u64,
+        ) -> Result<()> {
+            self.ensure_operation_open(Operation::Offers)?;
+            let caller = self.env().caller();
+
+            // Fetch user and validate seller status
+            let user = self
+                .users
+                .get(caller)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            if user.account_type != AccountType::Seller {
+                return Err(MarketplaceError::OnlySellersAllowed);
+            }
+
+            // Fetch the request and validate its existence
+            let mut request = self
+                .requests
+                .get(request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            match request.bidding_mode {
+                BiddingMode::Open => {}
+                BiddingMode::ReverseAuction { closes_at, .. } => {
+                    if self.env().block_timestamp() >= closes_at {
+                        return Err(MarketplaceError::BiddingWindowClosed);
+                    }
+                }
+                // Sealed requests only take offers through commit and reveal
+                BiddingMode::Sealed { .. } => return Err(MarketplaceError::WrongBiddingMode),
+            }
+
+            self.place_offer(caller, &user, &mut request, price, images, store_id)?;
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn commit_offer(&mut self, request_id: u64, commitment: Hash) -> Result<()> {
+            self.ensure_operation_open(Operation::Offers)?;
+            let caller = self.env().caller();
+
+            let user = self
+                .users
+                .get(caller)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            if user.account_type != AccountType::Seller {
+                return Err(MarketplaceError::OnlySellersAllowed);
+            }
+
+            let request = self
+                .requests
+                .get(request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            let BiddingMode::Sealed { commit_ends_at, .. } = request.bidding_mode else {
+                return Err(MarketplaceError::WrongBiddingMode);
+            };
+
+            if self.env().block_timestamp() >= commit_ends_at {
+                return Err(MarketplaceError::BiddingWindowClosed);
+            }
+
+            // Commitments follow the same rules as the offers they turn into
+            if self.is_expired(&request) {
+                return Err(MarketplaceError::RequestExpired);
+            }
+            if !request
+                .lifecycle
+                .can_transition_to(RequestLifecycle::AcceptedBySeller)
+            {
+                return Err(MarketplaceError::InvalidLifecycleTransition {
+                    from: request.lifecycle,
+                    to: RequestLifecycle::AcceptedBySeller,
+                });
+            }
+
+            if self.offer_commitments.contains((request_id, user.id)) {
+                return Err(MarketplaceError::DuplicateOffer);
+            }
+
+            let mut seller_ids = self.request_commitments.get(request_id).unwrap_or_default();
+            if seller_ids.len() >= self.config.max_offers_per_request as usize {
+                return Err(MarketplaceError::TooManyOffers);
+            }
+
+            self.offer_commitments
+                .insert((request_id, user.id), &commitment);
+            seller_ids.push(user.id);
+            self.request_commitments.insert(request_id, &seller_ids);
+
+            self.env().emit_event(OfferCommitted {
+                request_id,
+                seller_id: user.id,
+                commitment,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn reveal_offer(
+            &mut self,
+            request_id: u64,
+            price: Balance,
+            salt: [u8; 32],
+            images: Vec<String>,
+            store_id: u64,
+        ) -> Result<()> {
+            self.ensure_operation_open(Operation::Offers)?;
+            let caller = self.env().caller();
+
+            let user = self
+                .users
+                .get(caller)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            let mut request = self
+                .requests
+                .get(request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            let BiddingMode::Sealed {
+                commit_ends_at,
+                reveal_ends_at,
+            } = request.bidding_mode
+            else {
+                return Err(MarketplaceError::WrongBiddingMode);
+            };
+
+            let now = self.env().block_timestamp();
+            if now < commit_ends_at {
+                return Err(MarketplaceError::BiddingInProgress);
+            }
+            if now >= reveal_ends_at {
+                return Err(MarketplaceError::BiddingWindowClosed);
+            }
+
+            let commitment = self
+                .offer_commitments
+                .get((request_id, user.id))
+                .ok_or(MarketplaceError::NoCommitment)?;
+
+            // The commitment is bound to the request and the seller, so it cannot be
+            // replayed by another seller or on another request
+            let revealed = Hash::from(
+                self.env()
+                    .hash_encoded::<Blake2x256, _>(&(request_id, caller, price, salt)),
+            );
+            if revealed != commitment {
+                return Err(MarketplaceError::InvalidReveal);
+            }
+
+            self.offer_commitments.remove((request_id, user.id));
+            let mut seller_ids = self.request_commitments.get(request_id).unwrap_or_default();
+            seller_ids.retain(|id| *id != user.id);
+            self.request_commitments.insert(request_id, &seller_ids);
+
+            let offer_id =
+                self.place_offer(caller, &user, &mut request, price, images, store_id)?;
+
+            self.env().emit_event(OfferRevealed {
+                request_id,
+                seller_id: user.id,
+                offer_id,
+                price,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn discard_unrevealed_commitments(&mut self, request_id: u64) -> Result<()> {
+            self.ensure_not_paused()?;
+            let request = self
+                .requests
+                .get(request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            let BiddingMode::Sealed { reveal_ends_at, .. } = request.bidding_mode else {
+                return Err(MarketplaceError::WrongBiddingMode);
+            };
+
+            if self.env().block_timestamp() < reveal_ends_at {
+                return Err(MarketplaceError::BiddingInProgress);
+            }
+
+            self.discard_commitments(request_id);
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn withdraw_offer(&mut self, offer_id: u64) -> Result<()> {
+            // Open while paused, see `pause`
+            let caller = self.env().caller();
+
+            let mut offer = self
+                .offers
+                .get(offer_id)
+                .ok_or(MarketplaceError::InvalidOffer)?;
+
+            if offer.authority != caller {
+                return Err(MarketplaceError::UnauthorizedSeller);
+            }
+
+            let mut request = self
+                .requests
+                .get(offer.request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            // Purchases are withdrawn by the buyer cancelling the request
+            if request.listing_id.is_some() {
+                return Err(MarketplaceError::FixedPriceOffer);
+            }
+
+            if offer.is_accepted {
+                self.revoke_acceptance(&mut request, &mut offer)?;
+            }
+
+            request.offer_ids.retain(|id| *id != offer_id);
+            if let Some(index) = request
+                .seller_ids
+                .iter()
+                .position(|id| *id == offer.seller_id)
+            {
+                request.seller_ids.remove(index);
+            }
+            if request.offer_ids.is_empty()
+                && request.lifecycle == RequestLifecycle::AcceptedBySeller
+            {
+                self.transition(&mut request, RequestLifecycle::Pending)?;
+            }
+            self.requests.insert(request.id, &request);
+            self.offers.remove(offer_id);
+            self.seller_offer_ids.remove((request.id, offer.seller_id));
+
+            self.env().emit_event(OfferRemoved {
+                offer_id,
+                seller_address: caller,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn update_offer(
+            &mut self,
+            offer_id: u64,
+            price: Balance,
+            images: Vec<String>,
+        ) -> Result<()> {
+            self.ensure_operation_open(Operation::Offers)?;
+            let caller = self.env().caller();
+
+            let mut offer = self
+                .offers
+                .get(offer_id)
+                .ok_or(MarketplaceError::InvalidOffer)?;
+
+            if offer.authority != caller {
+                return Err(MarketplaceError::UnauthorizedSeller);
+            }
+
+            if offer.is_void {
+                return Err(MarketplaceError::InvalidOffer);
+            }
+
+            let mut request = self
+                .requests
+                .get(offer.request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            // Purchases keep the listing price
+            if request.listing_id.is_some() {
+                return Err(MarketplaceError::FixedPriceOffer);
+            }
+
+            match request.bidding_mode {
+                BiddingMode::Open => {}
+                // Auction bids can be lowered or raised until the auction closes
+                BiddingMode::ReverseAuction { closes_at, .. } => {
+                    if self.env().block_timestamp() >= closes_at {
+                        return Err(MarketplaceError::BiddingWindowClosed);
+                    }
+                }
+                // Revealed sealed bids are final
+                BiddingMode::Sealed { .. } => return Err(MarketplaceError::WrongBiddingMode),
+            }
+
+            if offer.is_accepted {
+                self.revoke_acceptance(&mut request, &mut offer)?;
+                self.requests.insert(request.id, &request);
+            }
+
+            offer.price = price;
+            offer.images = images.clone();
+            offer.counter_price = None;
+            offer.updated_at = self.env().block_timestamp();
+            self.offers.insert(offer_id, &offer);
+
+            self.env().emit_event(OfferUpdated {
+                offer_id,
+                seller_address: caller,
+                price,
+                images,
+                updated_at: offer.updated_at,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn counter_offer(&mut self, offer_id: u64, price: Balance) -> Result<()> {
+            self.ensure_operation_open(Operation::Offers)?;
+            let caller = self.env().caller();
+
+            let mut offer = self
+                .offers
+                .get(offer_id)
+                .ok_or(MarketplaceError::InvalidOffer)?;
+
+            let request = self
+                .requests
+                .get(offer.request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            let buyer = self
+                .users
+                .get(caller)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            if request.buyer_id != buyer.id {
+                return Err(MarketplaceError::UnauthorizedBuyer);
+            }
+
+            // Purchases keep the listing price
+            if request.listing_id.is_some() {
+                return Err(MarketplaceError::FixedPriceOffer);
+            }
+
+            // Auction and sealed prices are set by the bidding alone
+            if request.bidding_mode != BiddingMode::Open {
+                return Err(MarketplaceError::WrongBiddingMode);
+            }
+
+            if offer.is_void {
+                return Err(MarketplaceError::InvalidOffer);
+            }
+
+            if offer.is_accepted {
+                return Err(MarketplaceError::OfferAlreadyAccepted);
+            }
+
+            if offer.counter_price.is_some() {
+                return Err(MarketplaceError::CounterOfferPending);
+            }
+
+            if offer.negotiation_rounds >= MAX_NEGOTIATION_ROUNDS {
+                return Err(MarketplaceError::NegotiationLimitReached);
+            }
+
+            offer.counter_price = Some(price);
+            offer.negotiation_rounds = offer.negotiation_rounds.checked_add(1).unwrap();
+            offer.updated_at = self.env().block_timestamp();
+            self.offers.insert(offer_id, &offer);
+
+            self.env().emit_event(OfferCountered {
+                offer_id,
+                request_id: offer.request_id,
+                counter_price: price,
+                negotiation_rounds: offer.negotiation_rounds,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn respond_to_counter_offer(
+            &mut self,
+            offer_id: u64,
+            response: CounterOfferResponse,
+        ) -> Result<()> {
+            self.ensure_operation_open(Operation::Offers)?;
+            let caller = self.env().caller();
+
+            let mut offer = self
+                .offers
+                .get(offer_id)
+                .ok_or(MarketplaceError::InvalidOffer)?;
+
+            if offer.authority != caller {
+                return Err(MarketplaceError::UnauthorizedSeller);
+            }
+
+            if offer.is_void {
+                return Err(MarketplaceError::InvalidOffer);
+            }
+
+            if offer.is_accepted {
+                return Err(MarketplaceError::OfferAlreadyAccepted);
+            }
+
+            let mut request = self
+                .requests
+                .get(offer.request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            if request.listing_id.is_some() {
+                return Err(MarketplaceError::FixedPriceOffer);
+            }
+
+            if request.bidding_mode != BiddingMode::Open {
+                return Err(MarketplaceError::WrongBiddingMode);
+            }
+
+            self.lock_if_due(&mut request)?;
+            if !request.lifecycle.takes_offers()
+                && request.lifecycle != RequestLifecycle::AcceptedByBuyer
+            {
+                return Err(MarketplaceError::RequestLocked);
+            }
+
+            let counter_price = offer
+                .counter_price
+                .ok_or(MarketplaceError::NoCounterOffer)?;
+
+            match response {
+                CounterOfferResponse::Accept => offer.price = counter_price,
+                CounterOfferResponse::Reject => {}
+                CounterOfferResponse::Counter(price) => {
+                    if offer.negotiation_rounds >= MAX_NEGOTIATION_ROUNDS {
+                        return Err(MarketplaceError::NegotiationLimitReached);
+                    }
+                    offer.price = price;
+                    offer.negotiation_rounds = offer.negotiation_rounds.checked_add(1).unwrap();
+                }
+            }
+            offer.counter_price = None;
+            offer.updated_at = self.env().block_timestamp();
+            self.offers.insert(offer_id, &offer);
+
+            self.env().emit_event(CounterOfferResponded {
+                offer_id,
+                request_id: offer.request_id,
+                response,
+                price: offer.price,
+                negotiation_rounds: offer.negotiation_rounds,
+            });
+            Ok(())
+        }
+
+        #[ink(message, payable)]
+        pub fn accept_offer(&mut self, offer_id: u64, expected_price: Balance) -> Result<()> {
+            self.ensure_operation_open(Operation::Acceptances)?;
+            let caller = self.env().caller();
+
+            // Fetch the offer and validate its existence
+            let mut offer = self
+                .offers
+                .get(offer_id)
+                .ok_or(MarketplaceError::InvalidOffer)?;
+
+            let request_id = offer.request_id;
+
+            // Fetch the request and validate its existence
+            let mut request = self
+                .requests
+                .get(request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            // Ensure the caller is the authorized buyer for this request
+            let buyer = self
+                .users
+                .get(caller)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            if buyer.account_type != AccountType::Buyer {
+                return Err(MarketplaceError::OnlyBuyersAllowed);
+            }
+
+            if request.buyer_id != buyer.id {
+                return Err(MarketplaceError::UnauthorizedBuyer);
+            }
+
+            match request.bidding_mode {
+                BiddingMode::Open => {}
+                // Sealed offers can only be compared once every price is revealed
+                BiddingMode::Sealed { reveal_ends_at, .. } => {
+                    if self.env().block_timestamp() < reveal_ends_at {
+                        return Err(MarketplaceError::BiddingInProgress);
+                    }
+                }
+                // Auctions pick their winner through `settle_auction`
+                BiddingMode::ReverseAuction { .. } => {
+                    return Err(MarketplaceError::WrongBiddingMode)
+                }
+            }
+
+            // Check if the offer has already been accepted
+            if offer.is_accepted {
+                return Err(MarketplaceError::OfferAlreadyAccepted);
+            }
+
+            if offer.price != expected_price {
+                return Err(MarketplaceError::OfferPriceChanged);
+            }
+
+            // The accepted offer can be switched until the lock window elapses
+            self.lock_if_due(&mut request)?;
+            self.transition(&mut request, RequestLifecycle::AcceptedByBuyer)?;
+
+            // Return the deposit held for a previously accepted offer
+            self.refund_escrow(&mut request, caller)?;
+
+            // The buyer must deposit exactly the offered price
+            self.collect_deposit(request.payment_asset, caller, offer.price)?;
+
+            self.accept(&mut request, &mut offer, caller);
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn settle_auction(&mut self, request_id: u64) -> Result<()> {
+            self.ensure_operation_open(Operation::Acceptances)?;
+            let mut request = self
+                .requests
+                .get(request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            let BiddingMode::ReverseAuction {
+                closes_at,
+                reserve_price,
+            } = request.bidding_mode
+            else {
+                return Err(MarketplaceError::WrongBiddingMode);
+            };
+
+            if self.env().block_timestamp() < closes_at {
+                return Err(MarketplaceError::BiddingInProgress);
+            }
+
+            // An auction settles once, while the full reserve is still escrowed
+            if !matches!(
+                request.lifecycle,
+                RequestLifecycle::Pending | RequestLifecycle::AcceptedBySeller
+            ) || request.escrowed_amount != reserve_price
+            {
+                return Err(MarketplaceError::OfferAlreadyAccepted);
+            }
+
+            let buyer_address = self
+                .user_ids
+                .get(request.buyer_id)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            let winner = request
+                .offer_ids
+                .iter()
+                .filter_map(|offer_id| self.offers.get(*offer_id))
+                .filter(|offer| !offer.is_void && offer.price <= reserve_price)
+                .min_by_key(|offer| (offer.price, offer.created_at, offer.id));
+
+            let Some(mut offer) = winner else {
+                self.cancel(&mut request, buyer_address)?;
+                self.requests.insert(request_id, &request);
+
+                self.env().emit_event(AuctionSettled {
+                    request_id,
+                    winning_offer_id: None,
+                    price: 0,
+                    settled_at: self.env().block_timestamp(),
+                });
+                return Ok(());
+            };
+
+            self.transition(&mut request, RequestLifecycle::AcceptedByBuyer)?;
+
+            // Keep the winning price in escrow and return the rest of the reserve
+            let refund = request.escrowed_amount.checked_sub(offer.price).unwrap();
+            if refund > 0 {
+                self.pay_out(request.payment_asset, buyer_address, refund)?;
+                self.env().emit_event(EscrowRefunded {
+                    request_id,
+                    buyer_address,
+                    amount: refund,
+                });
+            }
+
+            self.accept(&mut request, &mut offer, buyer_address);
+
+            self.env().emit_event(AuctionSettled {
+                request_id,
+                winning_offer_id: Some(offer.id),
+                price: offer.price,
+                settled_at: self.env().block_timestamp(),
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn mark_request_as_completed(&mut self, request_id: u64) -> Result<()> {
+            self.ensure_not_paused()?;
+            let caller = self.env().caller();
+
+            let mut request = self
+                .requests
+                .get(request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            let buyer = self
+                .users
+                .get(caller)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            if buyer.account_type != AccountType::Buyer {
+                return Err(MarketplaceError::OnlyBuyersAllowed);
+            }
+
+            if request.buyer_id != buyer.id {
+                return Err(MarketplaceError::UnauthorizedBuyer);
+            }
+
+            // Completion is only possible once the lock window has elapsed
+            self.lock_if_due(&mut request)?;
+            self.transition(&mut request, RequestLifecycle::Completed)?;
+            self.release_escrow(&mut request)?;
+            self.requests.insert(request_id, &request);
+
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn cancel_request(&mut self, request_id: u64) -> Result<()> {
+            // Open while paused, see `pause`
+            let caller = self.env().caller();
+
+            let mut request = self
+                .requests
+                .get(request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            let user = self
+                .users
+                .get(caller)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            if request.buyer_id != user.id {
+                return Err(MarketplaceError::UnauthorizedBuyer);
+            }
+
+            self.ensure_auction_not_closed(&request)?;
+            self.cancel(&mut request, caller)?;
+            self.requests.insert(request_id, &request);
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn close_expired_request(&mut self, request_id: u64) -> Result<()> {
+            self.ensure_not_paused()?;
+            let mut request = self
+                .requests
+                .get(request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            if !self.is_expired(&request) {
+                return Err(MarketplaceError::RequestNotExpired);
+            }
+
+            if !matches!(
+                request.lifecycle,
+                RequestLifecycle::Pending | RequestLifecycle::AcceptedBySeller
+            ) {
+                return Err(MarketplaceError::OfferAlreadyAccepted);
+            }
+
+            let buyer_address = self
+                .user_ids
+                .get(request.buyer_id)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            self.cancel(&mut request, buyer_address)?;
+            self.remove_request(&request, buyer_address);
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn add_category(&mut self, name: String) -> Result<()> {
+            self.ensure_role(Role::Moderator)?;
+
+            self.category_counter = self.category_counter.checked_add(1).unwrap();
+            let category = Category {
+                id: self.category_counter,
+                name: name.clone(),
+            };
+            self.categories.insert(self.category_counter, &category);
+
+            self.env().emit_event(CategoryAdded {
+                category_id: self.category_counter,
+                name,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn remove_category(&mut self, category_id: u64) -> Result<()> {
+            self.ensure_role(Role::Moderator)?;
+            if self.categories.take(category_id).is_none() {
+                return Err(MarketplaceError::InvalidCategory);
+            }
+
+            self.env().emit_event(CategoryRemoved { category_id });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn set_cancellation_fee_bps(&mut self, fee_bps: u16) -> Result<()> {
+            self.ensure_role(Role::FeeManager)?;
+            if fee_bps > BASIS_POINTS {
+                return Err(MarketplaceError::InvalidFee);
+            }
+            self.config.cancellation_fee_bps = fee_bps;
+
+            self.env().emit_event(ConfigUpdated {
+                account: self.env().caller(),
+                config: self.config.clone(),
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn set_config(&mut self, config: MarketplaceConfig) -> Result<()> {
+            self.ensure_role(Role::Admin)?;
+            config.validate()?;
+            self.config = config.clone();
+
+            self.env().emit_event(ConfigUpdated {
+                account: self.env().caller(),
+                config,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_config(&self) -> MarketplaceConfig {
+            self.config.clone()
+        }
+
+        #[ink(message)]
+        pub fn lock_request(&mut self, request_id: u64) -> Result<()> {
+            self.ensure_not_paused()?;
+            let mut request = self
+                .requests
+                .get(request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            if !self.lock_if_due(&mut request)? {
+                return Err(MarketplaceError::RequestNotLocked);
+            }
+            self.requests.insert(request_id, &request);
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn add_arbitrator(&mut self, arbitrator: AccountId) -> Result<()> {
+            self.grant_role(Role::Arbitrator, arbitrator)
+        }
+
+        #[ink(message)]
+        pub fn remove_arbitrator(&mut self, arbitrator: AccountId) -> Result<()> {
+            self.revoke_role(Role::Arbitrator, arbitrator)
+        }
+
+        #[ink(message)]
+        pub fn grant_role(&mut self, role: Role, account: AccountId) -> Result<()> {
+            self.ensure_role_manager(role)?;
+            if !self.roles.contains((account, role)) {
+                self.roles.insert((account, role), &());
+                if role == Role::Arbitrator {
+                    self.arbitrators.push(account);
+                }
+            }
+
+            self.env().emit_event(RoleGranted {
+                account,
+                role,
+                granted_by: self.env().caller(),
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn revoke_role(&mut self, role: Role, account: AccountId) -> Result<()> {
+            self.ensure_role_manager(role)?;
+            if self.roles.take((account, role)).is_some() && role == Role::Arbitrator {
+                self.arbitrators.retain(|arbitrator| *arbitrator != account);
+            }
+
+            self.env().emit_event(RoleRevoked {
+                account,
+                role,
+                revoked_by: self.env().caller(),
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn has_role(&self, role: Role, account: AccountId) -> bool {
+            if role == Role::Arbitrator {
+                return self.roles.contains((account, role));
+            }
+            account == self.owner
+                || self.roles.contains((account, Role::Admin))
+                || self.roles.contains((account, role))
+        }
+
+        // Exits before a request locks stay open, as the lock window keeps running
+        #[ink(message)]
+        pub fn pause(&mut self) -> Result<()> {
+            self.set_paused(true)
+        }
+
+        #[ink(message)]
+        pub fn unpause(&mut self) -> Result<()> {
+            self.set_paused(false)
+        }
+
+        #[ink(message)]
+        pub fn set_circuit_breaker(&mut self, operation: Operation, paused: bool) -> Result<()> {
+            self.ensure_role(Role::Admin)?;
+            let breaker = self.circuit_breakers.get_mut(operation);
+            if *breaker == paused {
+                return Ok(());
+            }
+            *breaker = paused;
+
+            self.env().emit_event(CircuitBreakerChanged {
+                account: self.env().caller(),
+                operation,
+                paused,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn is_paused(&self) -> bool {
+            self.paused
+        }
+
+        #[ink(message)]
+        pub fn get_circuit_breakers(&self) -> CircuitBreakers {
+            self.circuit_breakers.clone()
+        }
+
+        #[ink(message)]
+        pub fn transfer_ownership(&mut self, new_owner: AccountId) -> Result<()> {
+            let caller = self.env().caller();
+            if caller != self.owner {
+                return Err(MarketplaceError::NotOwner);
+            }
+            if new_owner == caller {
+                return Err(MarketplaceError::AlreadyOwner);
+            }
+            self.owner = new_owner;
+
+            self.env().emit_event(OwnershipTransferred {
+                previous_owner: caller,
+                new_owner,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_owner(&self) -> AccountId {
+            self.owner
+        }
+
+        #[ink(message)]
+        pub fn open_dispute(&mut self, request_id: u64, evidence_uri: String) -> Result<()> {
+            self.ensure_not_paused()?;
+            let caller = self.env().caller();
+
+            let mut request = self
+                .requests
+                .get(request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            self.ensure_request_participant(&request, caller)?;
+
+            self.lock_if_due(&mut request)?;
+            self.transition(&mut request, RequestLifecycle::Disputed)?;
+
+            // The buyer and the locked seller never sit on their own panel, and an
+            // odd panel cannot split evenly
+            let parties = [
+                self.user_ids.get(request.buyer_id),
+                self.user_ids.get(request.locked_seller_id),
+            ];
+            let mut panel: Vec<AccountId> = self
+                .arbitrators
+                .iter()
+                .filter(|arbitrator| !parties.contains(&Some(**arbitrator)))
+                .copied()
+                .collect();
+            if panel.is_empty() {
+                return Err(MarketplaceError::NoArbitrators);
+            }
+            if panel.len().is_multiple_of(2) {
+                panel.pop();
+            }
+
+            let now = self.env().block_timestamp();
+            let dispute = Dispute {
+                request_id,
+                opened_by: caller,
+                evidence: Vec::from([Evidence {
+                    submitted_by: caller,
+                    uri: evidence_uri.clone(),
+                    submitted_at: now,
+                }]),
+                voters: Vec::new(),
+                votes_to_release: 0,
+                votes_to_refund: 0,
+                panel,
+                ruling: None,
+                created_at: now,
+                updated_at: now,
+            };
+            self.disputes.insert(request_id, &dispute);
+            self.requests.insert(request_id, &request);
+
+            self.env().emit_event(DisputeOpened {
+                request_id,
+                opened_by: caller,
+                evidence_uri,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn add_dispute_evidence(
+            &mut self,
+            request_id: u64,
+            evidence_uri: String,
+        ) -> Result<()> {
+            self.ensure_not_paused()?;
+            let caller = self.env().caller();
+
+            let request = self
+                .requests
+                .get(request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            self.ensure_request_participant(&request, caller)?;
+
+            if request.lifecycle != RequestLifecycle::Disputed {
+                return Err(MarketplaceError::RequestNotDisputed);
+            }
+
+            let mut dispute = self
+                .disputes
+                .get(request_id)
+                .ok_or(MarketplaceError::RequestNotDisputed)?;
+
+            let now = self.env().block_timestamp();
+            dispute.evidence.push(Evidence {
+                submitted_by: caller,
+                uri: evidence_uri.clone(),
+                submitted_at: now,
+            });
+            dispute.updated_at = now;
+            self.disputes.insert(request_id, &dispute);
+
+            self.env().emit_event(DisputeEvidenceAdded {
+                request_id,
+                submitted_by: caller,
+                evidence_uri,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn vote_on_dispute(&mut self, request_id: u64, ruling: DisputeRuling) -> Result<()> {
+            self.ensure_not_paused()?;
+            let caller = self.env().caller();
+
+            if !self.has_role(Role::Arbitrator, caller) {
+                return Err(MarketplaceError::NotArbitrator);
+            }
+
+            let mut request = self
+                .requests
+                .get(request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            if request.lifecycle != RequestLifecycle::Disputed {
+                return Err(MarketplaceError::RequestNotDisputed);
+            }
+
+            if self.users.get(caller).is_some_and(|user| {
+                user.id == request.buyer_id || user.id == request.locked_seller_id
+            }) {
+                return Err(MarketplaceError::PartyToDispute);
+            }
+
+            let mut dispute = self
+                .disputes
+                .get(request_id)
+                .ok_or(MarketplaceError::RequestNotDisputed)?;
+
+            if !dispute.panel.contains(&caller) {
+                return Err(MarketplaceError::NotArbitrator);
+            }
+
+            if dispute.voters.contains(&caller) {
+                return Err(MarketplaceError::AlreadyVoted);
+            }
+
+            let now = self.env().block_timestamp();
+            dispute.voters.push(caller);
+            let votes = match ruling {
+                DisputeRuling::ReleaseToSeller => {
+                    dispute.votes_to_release = dispute.votes_to_release.checked_add(1).unwrap();
+                    dispute.votes_to_release
+                }
+                DisputeRuling::RefundBuyer => {
+                    dispute.votes_to_refund = dispute.votes_to_refund.checked_add(1).unwrap();
+                    dispute.votes_to_refund
+                }
+            };
+            dispute.updated_at = now;
+
+            self.env().emit_event(DisputeVoteCast {
+                request_id,
+                arbitrator: caller,
+                ruling,
+            });
+
+            // A strict majority of the panel members still holding the role settles
+            // the dispute
+            let panel_size = dispute
+                .panel
+                .iter()
+                .filter(|arbitrator| self.roles.contains((**arbitrator, Role::Arbitrator)))
+                .count();
+            if votes as usize > panel_size / 2 {
+                self.rule_dispute(&mut request, &mut dispute, ruling)?;
+            }
+
+            self.disputes.insert(request_id, &dispute);
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn settle_dispute(&mut self, request_id: u64) -> Result<()> {
+            self.ensure_not_paused()?;
+            let mut request = self
+                .requests
+                .get(request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            if request.lifecycle != RequestLifecycle::Disputed {
+                return Err(MarketplaceError::RequestNotDisputed);
+            }
+
+            let mut dispute = self
+                .disputes
+                .get(request_id)
+                .ok_or(MarketplaceError::RequestNotDisputed)?;
+
+            if self.env().block_timestamp() < dispute.created_at.saturating_add(DISPUTE_TIMEOUT) {
+                return Err(MarketplaceError::DisputeNotExpired);
+            }
+
+            // Follow the votes cast so far; a tie refunds the buyer
+            let ruling = if dispute.votes_to_release > dispute.votes_to_refund {
+                DisputeRuling::ReleaseToSeller
+            } else {
+                DisputeRuling::RefundBuyer
+            };
+            self.rule_dispute(&mut request, &mut dispute, ruling)?;
+            self.disputes.insert(request_id, &dispute);
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn submit_review(
+            &mut self,
+            request_id: u64,
+            rating: u8,
+            comment_uri: String,
+        ) -> Result<()> {
+            self.ensure_not_paused()?;
+            let caller = self.env().caller();
+
+            let request = self
+                .requests
+                .get(request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+
+            let reviewer = self
+                .users
+                .get(caller)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            let reviewee_id = if reviewer.id == request.buyer_id {
+                request.locked_seller_id
+            } else if reviewer.id == request.locked_seller_id {
+                request.buyer_id
+            } else {
+                return Err(MarketplaceError::NotRequestParticipant);
+            };
+
+            if request.lifecycle != RequestLifecycle::Completed {
+                return Err(MarketplaceError::RequestNotCompleted);
+            }
+
+            if !(1..=5).contains(&rating) {
+                return Err(MarketplaceError::InvalidRating);
+            }
+
+            if self.request_reviews.contains((request_id, reviewer.id)) {
+                return Err(MarketplaceError::AlreadyReviewed);
+            }
+
+            let reviewee_address = self
+                .user_ids
+                .get(reviewee_id)
+                .ok_or(MarketplaceError::InvalidUser)?;
+            let mut reviewee = self
+                .users
+                .get(reviewee_address)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            self.review_counter = self.review_counter.checked_add(1).unwrap();
+            let review = Review {
+                id: self.review_counter,
+                request_id,
+                reviewer_id: reviewer.id,
+                reviewee_id,
+                rating,
+                comment_uri,
+                created_at: self.env().block_timestamp(),
+            };
+            self.reviews.insert(self.review_counter, &review);
+            self.request_reviews
+                .insert((request_id, reviewer.id), &self.review_counter);
+
+            let mut review_ids = self.user_review_ids.get(reviewee_id).unwrap_or_default();
+            review_ids.push(self.review_counter);
+            self.user_review_ids.insert(reviewee_id, &review_ids);
+
+            // Fold the rating into the reviewee's aggregated reputation
+            let reputation = &mut reviewee.reputation;
+            reputation.review_count = reputation.review_count.checked_add(1).unwrap();
+            reputation.rating_sum = reputation.rating_sum.checked_add(rating as u64).unwrap();
+            reputation.recent_ratings.push(rating);
+            if reputation.recent_ratings.len() > RECENT_RATINGS_WINDOW {
+                reputation.recent_ratings.remove(0);
+            }
+            let recent_sum: u32 = reputation.recent_ratings.iter().map(|r| *r as u32).sum();
+            let recent_count = u32::try_from(reputation.recent_ratings.len()).unwrap();
+            reputation.recent_average = recent_sum
+                .checked_mul(100)
+                .unwrap()
+                .checked_div(recent_count)
+                .unwrap();
+            self.users.insert(reviewee_address, &reviewee);
+
+            self.env().emit_event(ReviewSubmitted {
+                request_id,
+                reviewee_id,
+                review_id: self.review_counter,
+                reviewer_id: reviewer.id,
+                rating,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn toggle_location(&mut self, enabled: bool) -> Result<()> {
+            self.ensure_not_paused()?;
+            let caller = self.env().caller();
+            let mut user = self
+                .users
+                .get(caller)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            user.location_enabled = enabled;
+
+            self.users.insert(caller, &user);
+            self.env().emit_event(LocationEnabled {
+                authority: caller,
+                location_enabled: enabled,
+                user_id: user.id,
+                created_at: self.env().block_timestamp(),
+                updated_at: self.env().block_timestamp(),
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn publish_contact_key(&mut self, request_id: u64, public_key: [u8; 32]) -> Result<()> {
+            self.ensure_not_paused()?;
+            let caller = self.env().caller();
+            let (key, mut exchange, is_buyer) = self.contact_exchange_of(request_id, caller)?;
+
+            // A new key voids the contact the counterparty encrypted to the old one
+            if is_buyer {
+                if exchange.buyer_key != Some(public_key) {
+                    exchange.seller_contact = None;
+                }
+                exchange.buyer_key = Some(public_key);
+            } else {
+                if exchange.seller_key != Some(public_key) {
+                    exchange.buyer_contact = None;
+                }
+                exchange.seller_key = Some(public_key);
+            }
+            self.contact_exchanges.insert(key, &exchange);
+
+            self.env().emit_event(ContactKeyPublished {
+                request_id,
+                user_address: caller,
+                public_key,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn share_contact(&mut self, request_id: u64, encrypted_contact: Vec<u8>) -> Result<()> {
+            self.ensure_not_paused()?;
+            if encrypted_contact.is_empty() || encrypted_contact.len() > MAX_CONTACT_LENGTH {
+                return Err(MarketplaceError::InvalidContact);
+            }
+
+            let caller = self.env().caller();
+            let (key, mut exchange, is_buyer) = self.contact_exchange_of(request_id, caller)?;
+
+            if is_buyer {
+                if exchange.seller_key.is_none() {
+                    return Err(MarketplaceError::ContactKeyMissing);
+                }
+                exchange.buyer_contact = Some(encrypted_contact);
+            } else {
+                if exchange.buyer_key.is_none() {
+                    return Err(MarketplaceError::ContactKeyMissing);
+                }
+                exchange.seller_contact = Some(encrypted_contact);
+            }
+            self.contact_exchanges.insert(key, &exchange);
+
+            self.env().emit_event(ContactShared {
+                request_id,
+                user_address: caller,
+            });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_contact_exchange(&self, request_id: u64) -> Result<ContactExchange> {
+            let (_, exchange, _) = self.contact_exchange_of(request_id, self.env().caller())?;
+            Ok(exchange)
+        }
+
+        #[ink(message)]
+        pub fn get_location_preference(&self) -> bool {
+            let caller = self.env().caller();
+
+            let user = self.users.get(caller).unwrap();
+            user.location_enabled
+        }
+
+        #[ink(message)]
+        pub fn get_user(&self, user_address: AccountId) -> Option<User> {
+            self.users
+                .get(user_address)
+                .map(|user| self.user_view(user))
+        }
+
+        #[ink(message)]
+        pub fn get_request(&self, request_id: u64) -> Option<Request> {
+            self.requests
+                .get(request_id)
+                .map(|request| self.request_view(request))
+        }
+
+        #[ink(message)]
+        pub fn get_offer(&self, offer_id: u64) -> Option<Offer> {
+            self.offers.get(offer_id)
+        }
+
+        #[ink(message)]
+        pub fn get_offer_by_request(&self, request_id: u64) -> Vec<Offer> {
+            let mut request_offers = Vec::new();
+            let request = self.requests.get(request_id).unwrap();
+            for offer_id in request.offer_ids.iter() {
+                if let Some(offer) = self.offers.get(*offer_id) {
+                    request_offers.push(offer.clone());
+                }
+            }
+            request_offers
+        }
+
+        #[ink(message)]
+        pub fn get_seller_offer_for_request(
+            &self,
+            request_id: u64,
+            seller_id: u64,
+        ) -> Option<Offer> {
+            self.seller_offer_ids
+                .get((request_id, seller_id))
+                .and_then(|offer_id| self.offers.get(offer_id))
+        }
+
+        #[ink(message)]
+        pub fn get_subscription(&self, store_id: u64) -> Option<Subscription> {
+            self.store_subscriptions.get(store_id)
+        }
+
+        #[ink(message)]
+        pub fn get_matched_requests(&self, seller_address: AccountId) -> Vec<Request> {
+            self.matched_request_ids
+                .get(seller_address)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|request_id| self.get_request(*request_id))
+                .collect()
+        }
+
+        #[ink(message)]
+        pub fn get_categories(&self) -> Vec<Category> {
+            (1..=self.category_counter)
+                .filter_map(|category_id| self.categories.get(category_id))
+                .collect()
+        }
+
+        #[ink(message)]
+        pub fn get_requests_by_category(
+            &self,
+            category_id: u64,
+            cursor: u64,
+            limit: u64,
+        ) -> (Vec<Request>, u64) {
+            let count = self.category_request_count.get(category_id).unwrap_or(0);
+            let end = cursor.saturating_add(limit).min(count);
+            let requests = (cursor..end)
+                .filter_map(|index| self.category_request_ids.get((category_id, index)))
+                .filter_map(|request_id| self.get_request(request_id))
+                .collect();
+            (requests, end.max(cursor))
+        }
+
+        #[ink(message)]
+        pub fn get_distance(
+            &self,
+            from_latitude: i128,
+            from_longitude: i128,
+            to_latitude: i128,
+            to_longitude: i128,
+        ) -> Result<u64> {
+            let from = Location::new(from_latitude, from_longitude)?;
+            let to = Location::new(to_latitude, to_longitude)?;
+            Ok(from.distance_to(&to))
+        }
+
+        #[ink(message)]
+        pub fn get_requests_near(
+            &self,
+            latitude: i128,
+            longitude: i128,
+            radius: u64,
+            limit: u64,
+        ) -> Vec<Request> {
+            let center = Location {
+                latitude,
+                longitude,
+            };
+            let radius = radius.min(MAX_SEARCH_RADIUS);
+
+            let mut nearby = Vec::new();
+            for cell in center.cells_within(radius) {
+                for index in 0..self.cell_request_count.get(cell).unwrap_or(0) {
+                    let Some(request) = self
+                        .cell_request_ids
+                        .get((cell, index))
+                        .and_then(|request_id| self.requests.get(request_id))
+                    else {
+                        continue;
+                    };
+                    if !request.lifecycle.takes_offers() || self.is_expired(&request) {
+                        continue;
+                    }
+                    // Search by what the caller is allowed to see of the location
+                    let request = self.request_view(request);
+                    let distance = center.distance_to(&request.location);
+                    if distance <= radius {
+                        nearby.push((distance, request));
+                    }
+                }
+            }
+            nearby.sort_by_key(|(distance, request)| (*distance, request.id));
+            nearby
+                .into_iter()
+                .take(usize::try_from(limit).unwrap_or(usize::MAX))
+                .map(|(_, request)| request)
+                .collect()
+        }
+
+        #[ink(message)]
+        pub fn get_stores_near(
+            &self,
+            latitude: i128,
+            longitude: i128,
+            radius: u64,
+            limit: u64,
+        ) -> Vec<Store> {
+            let center = Location {
+                latitude,
+                longitude,
+            };
+            let radius = radius.min(MAX_SEARCH_RADIUS);
+
+            let mut nearby = Vec::new();
+            for cell in center.cells_within(radius) {
+                for index in 0..self.cell_store_count.get(cell).unwrap_or(0) {
+                    let Some(store) = self
+                        .cell_store_ids
+                        .get((cell, index))
+                        .and_then(|store_id| self.get_store(store_id))
+                    else {
+                        continue;
+                    };
+                    if !store.is_active {
+                        continue;
+                    }
+                    let distance = center.distance_to(&store.location);
+                    if distance <= radius {
+                        nearby.push((distance, store));
+                    }
+                }
+            }
+            nearby.sort_by_key(|(distance, store)| (*distance, store.id));
+            nearby
+                .into_iter()
+                .take(usize::try_from(limit).unwrap_or(usize::MAX))
+                .map(|(_, store)| store)
+                .collect()
+        }
+
+        #[ink(message)]
+        pub fn get_user_requests(&self, user_address: AccountId) -> Vec<Request> {
+            let mut user_requests = Vec::new();
+            let user = self.users.get(user_address).unwrap();
+            for request_id in 0..=self.request_counter {
+                if let Some(request) = self.requests.get(request_id) {
+                    if request.buyer_id == user.id {
+                        user_requests.push(self.request_view(request));
+                    }
+                }
+            }
+            user_requests
+        }
+
+        #[ink(message)]
+        pub fn get_all_requests(&self) -> Vec<Request> {
+            let mut all_requests = Vec::new();
+            for request_id in 0..=self.request_counter {
+                if let Some(request) = self.requests.get(request_id) {
+                    all_requests.push(self.request_view(request));
+                }
+            }
+            all_requests
+        }
+
+        #[ink(message)]
+        pub fn get_active_requests(&self) -> Vec<Request> {
+            self.get_all_requests()
+                .into_iter()
+                .filter(|request| !self.is_expired(request))
+                .collect()
+        }
+
+        #[ink(message)]
+        pub fn get_expired_requests(&self) -> Vec<Request> {
+            self.get_all_requests()
+                .into_iter()
+                .filter(|request| self.is_expired(request))
+                .collect()
+        }
+
+        #[ink(message)]
+        pub fn get_user_stores(&self, user_address: AccountId) -> Vec<Store> {
+            let mut user_stores = Vec::new();
+            let store_ids = self.user_store_ids.get(user_address).unwrap_or_default();
+            for store_id in store_ids.iter() {
+                if let Some(store) = self.user_stores.get((user_address, *store_id)) {
+                    user_stores.push(store.clone());
+                }
+            }
+            user_stores
+        }
+
+        #[ink(message)]
+        pub fn get_store(&self, store_id: u64) -> Option<Store> {
+            let owner = self.store_owners.get(store_id)?;
+            self.user_stores.get((owner, store_id))
+        }
+
+        #[ink(message)]
+        pub fn get_store_owner(&self, store_id: u64) -> Option<AccountId> {
+            self.store_owners.get(store_id)
+        }
+
+        #[ink(message)]
+        pub fn get_listing(&self, listing_id: u64) -> Option<Listing> {
+            self.listings.get(listing_id)
+        }
+
+        #[ink(message)]
+        pub fn get_store_listings(&self, store_id: u64) -> Vec<Listing> {
+            self.store_listing_ids
+                .get(store_id)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|listing_id| self.listings.get(*listing_id))
+                .collect()
+        }
+
+        #[ink(message)]
+        pub fn list_stores(&self, from: u64, limit: u64) -> Vec<Store> {
+            (from.max(1)..=self.store_counter)
+                .filter_map(|store_id| self.get_store(store_id))
+                .take(usize::try_from(limit).unwrap_or(usize::MAX))
+                .collect()
+        }
+
+        #[ink(message)]
+        pub fn get_user_by_id(&self, user_id: u64) -> Option<User> {
+            if let Some(account_id) = self.user_ids.get(user_id) {
+                self.get_user(account_id) // Retrieve the user by the AccountId
+            } else {
+                None
+            }
+        }
+
+        #[ink(message)]
+        pub fn get_dispute(&self, request_id: u64) -> Option<Dispute> {
+            self.disputes.get(request_id)
+        }
+
+        #[ink(message)]
+        pub fn get_arbitrators(&self) -> Vec<AccountId> {
+            self.arbitrators.clone()
+        }
+
+        #[ink(message)]
+        pub fn get_user_reputation(&self, user_id: u64) -> Option<Reputation> {
+            self.get_user_by_id(user_id).map(|user| user.reputation)
+        }
+
+        #[ink(message)]
+        pub fn get_reviews_for_user(&self, user_id: u64, from: u64, limit: u64) -> Vec<Review> {
+            let review_ids = self.user_review_ids.get(user_id).unwrap_or_default();
+            review_ids
+                .iter()
+                .skip(usize::try_from(from).unwrap_or(usize::MAX))
+                .take(usize::try_from(limit).unwrap_or(usize::MAX))
+                .filter_map(|review_id| self.reviews.get(*review_id))
+                .collect()
+        }
+
+        #[ink(message)]
+        pub fn get_cancellation_fee_bps(&self) -> u16 {
+            self.config.cancellation_fee_bps
+        }
+
+        #[ink(message)]
+        pub fn get_escrowed_amount(&self, request_id: u64) -> Balance {
+            self.requests
+                .get(request_id)
+                .map(|request| request.escrowed_amount)
+                .unwrap_or_default()
+        }
+
+        #[ink(message)]
+        pub fn get_seller_offers(&self, seller_address: AccountId) -> Vec<Offer> {
+            let mut seller_offers = Vec::new();
+            for offer_id in 0..=self.offer_counter {
+                if let Some(offer) = self.offers.get(offer_id) {
+                    if offer.authority == seller_address {
+                        seller_offers.push(offer.clone());
+                    }
+                }
+            }
+            seller_offers
+        }
+
+        fn transition(&mut self, request: &mut Request, to: RequestLifecycle) -> Result<()> {
+            let from = request.lifecycle;
+            if !from.can_transition_to(to) {
+                return Err(MarketplaceError::InvalidLifecycleTransition { from, to });
+            }
+            if from == to {
+                return Ok(());
+            }
+            self.set_lifecycle(request, to);
+            Ok(())
+        }
+
+        fn set_lifecycle(&mut self, request: &mut Request, to: RequestLifecycle) {
+            let from = request.lifecycle;
+            match (from.takes_offers(), to.takes_offers()) {
+                (true, false) => self.unindex_request(request.location.cell(), request.id),
+                (false, true) => self.index_request(request.location.cell(), request.id),
+                _ => {}
+            }
+            if from.is_matched() && !to.is_matched() && request.locked_seller_id != 0 {
+                self.unmatch_users(request.buyer_id, request.locked_seller_id);
+            }
+            request.lifecycle = to;
+            request.updated_at = self.env().block_timestamp();
+            if to == RequestLifecycle::AcceptedByBuyer {
+                request.lock_at = request.updated_at.saturating_add(self.config.time_to_lock);
+            }
+            self.env().emit_event(RequestLifecycleChanged {
+                request_id: request.id,
+                from,
+                to,
+                updated_at: request.updated_at,
+            });
+        }
+
+        fn set_store_active(&mut self, store_id: u64, is_active: bool) -> Result<()> {
+            let caller = self.env().caller();
+            let mut store = self
+                .user_stores
+                .get((caller, store_id))
+                .ok_or(MarketplaceError::StoreNotFound)?;
+
+            store.is_active = is_active;
+            self.user_stores.insert((caller, store_id), &store);
+
+            self.env().emit_event(StoreUpdated {
+                seller_address: caller,
+                store_id,
+                store_name: store.name,
+                latitude: store.location.latitude,
+                longitude: store.location.longitude,
+                is_active,
+            });
+            Ok(())
+        }
+
+        fn place_offer(
+            &mut self,
+            caller: AccountId,
+            seller: &User,
+            request: &mut Request,
+            price: Balance,
+            images: Vec<String>,
+            store_id: u64,
+        ) -> Result<u64> {
+            let request_id = request.id;
+
+            // Offers are made from one of the seller's own, active stores
+            let store = self
+                .user_stores
+                .get((caller, store_id))
+                .ok_or(MarketplaceError::StoreNotFound)?;
+            if !store.is_active {
+                return Err(MarketplaceError::StoreInactive);
+            }
+
+            if self.is_expired(request) {
+                return Err(MarketplaceError::RequestExpired);
+            }
+
+            // A seller keeps at most one active offer per request
+            if self.seller_offer_ids.contains((request_id, seller.id)) {
+                return Err(MarketplaceError::DuplicateOffer);
+            }
+            if request.offer_ids.len() >= self.config.max_offers_per_request as usize {
+                return Err(MarketplaceError::TooManyOffers);
+            }
+
+            // Offers are only taken until the buyer accepts one
+            self.transition(request, RequestLifecycle::AcceptedBySeller)?;
+
+            let offer = self.insert_offer(caller, seller.id, request, price, images, store);
+            Ok(offer.id)
+        }
+
+        // The reserve of a reverse auction must already have been collected
+        fn insert_request(
+            &mut self,
+            buyer: &User,
+            name: String,
+            description: String,
+            images: Vec<String>,
+            location: Location,
+            params: RequestParams,
+        ) -> Request {
+            let RequestParams {
+                payment_asset,
+                expires_at,
+                bidding_mode,
+                category_id,
+                tags,
+            } = params;
+            let buyer_address = buyer.authority;
+            let buyer_id = buyer.id;
+            let escrowed_amount = match bidding_mode {
+                BiddingMode::ReverseAuction { reserve_price, .. } => reserve_price,
+                _ => 0,
+            };
+            self.request_counter = self.request_counter.checked_add(1).unwrap();
+            let new_request = Request {
+                id: self.request_counter,
+                name: name.clone(),
+                buyer_id,
+                sellers_price_quote: 0,
+                seller_ids: Vec::new(),
+                offer_ids: Vec::new(),
+                locked_seller_id: 0,
+                description: description.clone(),
+                images: images.clone(),
+                created_at: self.env().block_timestamp(),
+                lifecycle: RequestLifecycle::Pending,
+                location: location.clone(),
+                updated_at: self.env().block_timestamp(),
+                escrowed_amount,
+                payment_asset,
+                expires_at,
+                bidding_mode,
+                category_id,
+                tags: tags.clone(),
+                listing_id: None,
+                listing_quantity: 0,
+                lock_at: 0,
+            };
+
+            self.requests.insert(self.request_counter, &new_request);
+            self.index_request(location.cell(), self.request_counter);
+            if let Some(category_id) = category_id {
+                let count = self.category_request_count.get(category_id).unwrap_or(0);
+                self.category_request_ids
+                    .insert((category_id, count), &self.request_counter);
+                self.category_request_count
+                    .insert(category_id, &count.checked_add(1).unwrap());
+            }
+            // Events are public, so they follow the buyer's location preference
+            let public_location = if buyer.location_enabled {
+                location
+            } else {
+                location.coarsened()
+            };
+            self.env().emit_event(RequestCreated {
+                request_id: self.request_counter,
+                buyer_address,
+                request_name: name,
+                latitude: public_location.latitude,
+                longitude: public_location.longitude,
+                images,
+                lifecycle: 0,
+                description,
+                buyer_id,
+                seller_ids: Vec::new(),
+                sellers_price_quote: 0,
+                locked_seller_id: 0,
+                created_at: self.env().block_timestamp(),
+                updated_at: self.env().block_timestamp(),
+                payment_asset,
+                expires_at,
+                bidding_mode,
+                category_id,
+                tags,
+            });
+            new_request
+        }
+
+        fn insert_offer(
+            &mut self,
+            seller_address: AccountId,
+            seller_id: u64,
+            request: &mut Request,
+            price: Balance,
+            images: Vec<String>,
+            store: Store,
+        ) -> Offer {
+            let request_id = request.id;
+            let store_id = store.id;
+
+            // Increment offer counter and create new offer
+            self.offer_counter = self.offer_counter.checked_add(1).unwrap();
+
+            let new_offer = Offer {
+                id: self.offer_counter,
+                price,
+                images: images.clone(),
+                request_id,
+                store_id,
+                store_name: store.name.clone(),
+                store_location: store.location,
+                seller_id,
+                is_accepted: false,
+                created_at: self.env().block_timestamp(),
+                updated_at: self.env().block_timestamp(),
+                authority: seller_address,
+                payment_asset: request.payment_asset,
+                is_void: false,
+                counter_price: None,
+                negotiation_rounds: 0,
+            };
+
+            // Insert the new offer into storage
+            self.offers.insert(self.offer_counter, &new_offer);
+            self.seller_offer_ids
+                .insert((request_id, seller_id), &self.offer_counter);
+
+            // Update the request with the new seller and offer details
+            request.seller_ids.push(seller_id);
+            request.offer_ids.push(self.offer_counter);
+            self.requests.insert(request_id, request);
+
+            // Emit event for offer creation
+            self.env().emit_event(OfferCreated {
+                offer_id: self.offer_counter,
+                seller_address,
+                store_id,
+                store_name: store.name,
+                price,
+                request_id,
+                images,
+                seller_id,
+                seller_ids: request.seller_ids.clone(),
+                payment_asset: request.payment_asset,
+            });
+
+            new_offer
+        }
+
+        fn accept(&mut self, request: &mut Request, offer: &mut Offer, buyer_address: AccountId) {
+            // Update previous offers for the same request to set `is_accepted` to false
+            for offer_id in request.offer_ids.iter() {
+                if let Some(mut previous_offer) = self.offers.get(*offer_id) {
+                    if previous_offer.is_accepted && previous_offer.request_id == request.id {
+                        previous_offer.is_accepted = false;
+                        self.offers.insert(*offer_id, &previous_offer);
+
+                        // Emit event for un-accepting the previous offer
+                        self.env().emit_event(OfferAccepted {
+                            offer_id: previous_offer.id,
+                            buyer_address,
+                            is_accepted: false,
+                        });
+                    }
+                }
+            }
+
+            // Buyer and seller may now see each other's location and exchange
+            // contacts, instead of the seller of a previously accepted offer
+            if request.locked_seller_id != offer.seller_id {
+                if request.locked_seller_id != 0 {
+                    self.unmatch_users(request.buyer_id, request.locked_seller_id);
+                    self.contact_exchanges
+                        .remove((request.id, request.locked_seller_id));
+                }
+                self.match_users(request.buyer_id, offer.seller_id);
+            }
+
+            // Accept the current offer, dropping any counter price still pending
+            offer.is_accepted = true;
+            offer.counter_price = None;
+            self.offers.insert(offer.id, offer);
+            request.locked_seller_id = offer.seller_id;
+            request.sellers_price_quote = offer.price;
+            request.escrowed_amount = offer.price;
+            request.updated_at = self.env().block_timestamp();
+            self.requests.insert(request.id, request);
+
+            // Emit events for request and offer acceptance
+            self.env().emit_event(RequestAccepted {
+                request_id: request.id,
+                offer_id: offer.id,
+                seller_id: offer.seller_id,
+                updated_at: request.updated_at,
+                sellers_price_quote: offer.price,
+            });
+
+            self.env().emit_event(OfferAccepted {
+                offer_id: offer.id,
+                buyer_address,
+                is_accepted: true,
+            });
+        }
+
+        fn revoke_acceptance(&mut self, request: &mut Request, offer: &mut Offer) -> Result<()> {
+            // Settled auctions are final, as they cannot be settled a second time
+            if matches!(request.bidding_mode, BiddingMode::ReverseAuction { .. }) {
+                return Err(MarketplaceError::OfferAlreadyAccepted);
+            }
+            self.lock_if_due(request)?;
+            if !request.lifecycle.can_revoke_acceptance() {
+                return Err(MarketplaceError::InvalidLifecycleTransition {
+                    from: request.lifecycle,
+                    to: RequestLifecycle::AcceptedBySeller,
+                });
+            }
+            self.set_lifecycle(request, RequestLifecycle::AcceptedBySeller);
+
+            let buyer_address = self
+                .user_ids
+                .get(request.buyer_id)
+                .ok_or(MarketplaceError::InvalidUser)?;
+            self.refund_escrow(request, buyer_address)?;
+
+            self.contact_exchanges
+                .remove((request.id, request.locked_seller_id));
+            request.locked_seller_id = 0;
+            request.sellers_price_quote = 0;
+            offer.is_accepted = false;
+            offer.updated_at = self.env().block_timestamp();
+            self.offers.insert(offer.id, offer);
+
+            self.env().emit_event(OfferAccepted {
+                offer_id: offer.id,
+                buyer_address,
+                is_accepted: false,
+            });
+            Ok(())
+        }
+
+        fn remove_request(&mut self, request: &Request, buyer_address: AccountId) {
+            for offer_id in request.offer_ids.iter() {
+                self.offers.remove(*offer_id);
+            }
+            for seller_id in request.seller_ids.iter() {
+                self.seller_offer_ids.remove((request.id, *seller_id));
+            }
+            self.requests.remove(request.id);
+
+            self.env().emit_event(RequestRemoved {
+                request_id: request.id,
+                buyer_address,
+                removed_at: self.env().block_timestamp(),
+            });
+        }
+
+        fn discard_commitments(&mut self, request_id: u64) {
+            let seller_ids = self
+                .request_commitments
+                .take(request_id)
+                .unwrap_or_default();
+            for seller_id in seller_ids.iter() {
+                self.offer_commitments.remove((request_id, *seller_id));
+            }
+
+            self.env().emit_event(CommitmentsDiscarded {
+                request_id,
+                seller_ids,
+            });
+        }
+
+        fn cancel(&mut self, request: &mut Request, buyer_address: AccountId) -> Result<()> {
+            self.lock_if_due(request)?;
+            self.transition(request, RequestLifecycle::Cancelled)?;
+
+            let mut cancellation_fee = 0;
+            if request.escrowed_amount > 0 && request.locked_seller_id != 0 {
+                cancellation_fee = request
+                    .escrowed_amount
+                    .checked_mul(Balance::from(self.config.cancellation_fee_bps))
+                    .unwrap()
+                    .checked_div(Balance::from(BASIS_POINTS))
+                    .unwrap();
+            }
+            if cancellation_fee > 0 {
+                let seller_address = self
+                    .user_ids
+                    .get(request.locked_seller_id)
+                    .ok_or(MarketplaceError::InvalidUser)?;
+
+                request.escrowed_amount = request
+                    .escrowed_amount
+                    .checked_sub(cancellation_fee)
+                    .unwrap();
+                self.pay_out(request.payment_asset, seller_address, cancellation_fee)?;
+
+                self.env().emit_event(EscrowReleased {
+                    request_id: request.id,
+                    seller_address,
+                    amount: cancellation_fee,
+                });
+            }
+
+            let refunded = request.escrowed_amount;
+            self.refund_escrow(request, buyer_address)?;
+
+            // Contacts are only exchanged for requests that go ahead
+            if request.locked_seller_id != 0 {
+                self.contact_exchanges
+                    .remove((request.id, request.locked_seller_id));
+            }
+
+            // A cancelled purchase puts the items back on sale
+            if let Some(mut listing) = request.listing_id.and_then(|id| self.listings.get(id)) {
+                listing.stock = listing.stock.checked_add(request.listing_quantity).unwrap();
+                listing.updated_at = self.env().block_timestamp();
+                self.listings.insert(listing.id, &listing);
+            }
+
+            for offer_id in request.offer_ids.iter() {
+                if let Some(mut offer) = self.offers.get(*offer_id) {
+                    offer.is_accepted = false;
+                    offer.is_void = true;
+                    offer.counter_price = None;
+                    offer.updated_at = self.env().block_timestamp();
+                    self.offers.insert(*offer_id, &offer);
+                }
+            }
+            if self.request_commitments.contains(request.id) {
+                self.discard_commitments(request.id);
+            }
+
+            self.env().emit_event(RequestCancelled {
+                request_id: request.id,
+                buyer_address,
+                cancellation_fee,
+                refunded,
+                cancelled_at: self.env().block_timestamp(),
+            });
+            Ok(())
+        }
+
+        fn lock_if_due(&mut self, request: &mut Request) -> Result<bool> {
+            if request.lifecycle == RequestLifecycle::AcceptedByBuyer
+                && self.env().block_timestamp() > request.lock_at
+            {
+                self.transition(request, RequestLifecycle::RequestLocked)?;
+            }
+            Ok(request.lifecycle == RequestLifecycle::RequestLocked)
+        }
+
+        fn remove_subscription(&mut self, store_id: u64) -> bool {
+            let Some(subscription) = self.store_subscriptions.take(store_id) else {
+                return false;
+            };
+            for category_id in subscription.category_ids.iter() {
+                Self::swap_remove_id(
+                    &mut self.category_subscribers,
+                    &mut self.category_subscriber_count,
+                    &mut self.category_subscriber_positions,
+                    *category_id,
+                    store_id,
+                );
+            }
+
+            self.env().emit_event(SubscriptionRemoved {
+                seller_address: subscription.seller_address,
+                store_id,
+            });
+            true
+        }
+
+        // Matches against what the public may see of the buyer's location
+        fn notify_subscribers(&mut self, request: &Request, buyer: &User) {
+            let Some(category_id) = request.category_id else {
+                return;
+            };
+            let location = if buyer.location_enabled {
+                request.location.clone()
+            } else {
+                request.location.coarsened()
+            };
+            let count = self.category_subscriber_count.get(category_id).unwrap_or(0);
+            if count == 0 {
+                return;
+            }
+
+            let start = request.id.checked_rem(count).unwrap();
+            for step in 0..count.min(MAX_SUBSCRIBERS_CHECKED) {
+                let position = start.checked_add(step).unwrap().checked_rem(count).unwrap();
+                let Some(store_id) = self.category_subscribers.get((category_id, position)) else {
+                    continue;
+                };
+                let Some(subscription) = self.store_subscriptions.get(store_id) else {
+                    continue;
+                };
+                let Some(store) = self
+                    .user_stores
+                    .get((subscription.seller_address, store_id))
+                else {
+                    continue;
+                };
+                if !store.is_active {
+                    continue;
+                }
+
+                let distance = store.location.distance_to(&location);
+                if distance > subscription.radius {
+                    continue;
+                }
+
+                let mut inbox = self
+                    .matched_request_ids
+                    .get(subscription.seller_address)
+                    .unwrap_or_default();
+                if !inbox.contains(&request.id) {
+                    if inbox.len() >= MATCH_INBOX_SIZE {
+                        inbox.remove(0);
+                    }
+                    inbox.push(request.id);
+                    self.matched_request_ids
+                        .insert(subscription.seller_address, &inbox);
+                }
+
+                self.env().emit_event(RequestMatched {
+                    request_id: request.id,
+                    seller_address: subscription.seller_address,
+                    store_id,
+                    distance,
+                });
+            }
+        }
+
+        fn can_see_location(&self, viewer: AccountId, user: &User) -> bool {
+            if user.location_enabled || viewer == user.authority {
+                return true;
+            }
+            self.users
+                .get(viewer)
+                .map(|viewer| {
+                    self.matched_users
+                        .contains((viewer.id.min(user.id), viewer.id.max(user.id)))
+                })
+                .unwrap_or(false)
+        }
+
+        fn match_users(&mut self, user_id: u64, other_id: u64) {
+            let pair = (user_id.min(other_id), user_id.max(other_id));
+            let count = self.matched_users.get(pair).unwrap_or(0);
+            self.matched_users
+                .insert(pair, &count.checked_add(1).unwrap());
+        }
+
+        fn unmatch_users(&mut self, user_id: u64, other_id: u64) {
+            let pair = (user_id.min(other_id), user_id.max(other_id));
+            match self.matched_users.get(pair).unwrap_or(0) {
+                0 | 1 => self.matched_users.remove(pair),
+                count => {
+                    self.matched_users
+                        .insert(pair, &count.checked_sub(1).unwrap());
+                }
+            }
+        }
+
+        fn set_paused(&mut self, paused: bool) -> Result<()> {
+            self.ensure_role(Role::Admin)?;
+            if self.paused == paused {
+                return Ok(());
+            }
+            self.paused = paused;
+
+            let account = self.env().caller();
+            if paused {
+                self.env().emit_event(Paused { account });
+            } else {
+                self.env().emit_event(Unpaused { account });
+            }
+            Ok(())
+        }
+
+        fn ensure_not_paused(&self) -> Result<()> {
+            if self.paused {
+                return Err(MarketplaceError::ContractPaused);
+            }
+            Ok(())
+        }
+
+        fn ensure_operation_open(&self, operation: Operation) -> Result<()> {
+            self.ensure_not_paused()?;
+            if self.circuit_breakers.is_tripped(operation) {
+                return Err(MarketplaceError::ContractPaused);
+            }
+            Ok(())
+        }
+
+        fn ensure_role(&self, role: Role) -> Result<()> {
+            if !self.has_role(role, self.env().caller()) {
+                return Err(MarketplaceError::MissingRole);
+            }
+            Ok(())
+        }
+
+        fn ensure_role_manager(&self, role: Role) -> Result<()> {
+            if role == Role::Admin && self.env().caller() != self.owner {
+                return Err(MarketplaceError::NotOwner);
+            }
+            self.ensure_role(Role::Admin)
+        }
+
+        fn contact_exchange_of(
+            &self,
+            request_id: u64,
+            account: AccountId,
+        ) -> Result<((u64, u64), ContactExchange, bool)> {
+            let request = self
+                .requests
+                .get(request_id)
+                .ok_or(MarketplaceError::InvalidRequest)?;
+            if request.locked_seller_id == 0 || request.lifecycle == RequestLifecycle::Cancelled {
+                return Err(MarketplaceError::RequestNotAccepted);
+            }
+            self.ensure_request_participant(&request, account)?;
+
+            let is_buyer = self.user_ids.get(request.buyer_id) == Some(account);
+            // Keyed by seller too, so a revoked acceptance never leaks contacts to
+            // the next locked seller.
+            let key = (request_id, request.locked_seller_id);
+            let exchange = self.contact_exchanges.get(key).unwrap_or_default();
+            Ok((key, exchange, is_buyer))
+        }
+
+        fn user_view(&self, mut user: User) -> User {
+            if !self.can_see_location(self.env().caller(), &user) {
+                user.location = user.location.coarsened();
+            }
+            user
+        }
+
+        fn request_view(&self, mut request: Request) -> Request {
+            let viewer = self.env().caller();
+            let Some(buyer) = self
+                .user_ids
+                .get(request.buyer_id)
+                .and_then(|buyer_address| self.users.get(buyer_address))
+            else {
+                return request;
+            };
+
+            let is_locked_seller = request.lifecycle.is_matched()
+                && request.locked_seller_id != 0
+                && self.user_ids.get(request.locked_seller_id) == Some(viewer);
+            if !buyer.location_enabled && viewer != buyer.authority && !is_locked_seller {
+                request.location = request.location.coarsened();
+            }
+            request
+        }
+
+        // `counts` keeps the length of each list and `positions` the index of every entry
+        fn push_id<A: StorageKey, B: StorageKey, C: StorageKey>(
+            ids: &mut Mapping<(u64, u64), u64, A>,
+            counts: &mut Mapping<u64, u64, B>,
+            positions: &mut Mapping<(u64, u64), u64, C>,
+            key: u64,
+            id: u64,
+        ) {
+            if positions.contains((key, id)) {
+                return;
+            }
+            let count = counts.get(key).unwrap_or(0);
+            ids.insert((key, count), &id);
+            positions.insert((key, id), &count);
+            counts.insert(key, &count.checked_add(1).unwrap());
+        }
+
+        fn swap_remove_id<A: StorageKey, B: StorageKey, C: StorageKey>(
+            ids: &mut Mapping<(u64, u64), u64, A>,
+            counts: &mut Mapping<u64, u64, B>,
+            positions: &mut Mapping<(u64, u64), u64, C>,
+            key: u64,
+            id: u64,
+        ) {
+            let Some(position) = positions.take((key, id)) else {
+                return;
+            };
+            let last = counts.get(key).unwrap_or(1).checked_sub(1).unwrap();
+            if position != last {
+                if let Some(moved) = ids.get((key, last)) {
+                    ids.insert((key, position), &moved);
+                    positions.insert((key, moved), &position);
+                }
+            }
+            ids.remove((key, last));
+            if last == 0 {
+                counts.remove(key);
+            } else {
+                counts.insert(key, &last);
+            }
+        }
+
+        fn index_request(&mut self, cell: u64, request_id: u64) {
+            Self::push_id(
+                &mut self.cell_request_ids,
+                &mut self.cell_request_count,
+                &mut self.cell_request_positions,
+                cell,
+                request_id,
+            );
+        }
+
+        fn unindex_request(&mut self, cell: u64, request_id: u64) {
+            Self::swap_remove_id(
+                &mut self.cell_request_ids,
+                &mut self.cell_request_count,
+                &mut self.cell_request_positions,
+                cell,
+                request_id,
+            );
+        }
+
+        fn index_store(&mut self, cell: u64, store_id: u64) {
+            Self::push_id(
+                &mut self.cell_store_ids,
+                &mut self.cell_store_count,
+                &mut self.cell_store_positions,
+                cell,
+                store_id,
+            );
+        }
+
+        fn unindex_store(&mut self, cell: u64, store_id: u64) {
+            Self::swap_remove_id(
+                &mut self.cell_store_ids,
+                &mut self.cell_store_count,
+                &mut self.cell_store_positions,
+                cell,
+                store_id,
+            );
+        }
+
+        fn ensure_category(&self, category_id: Option<u64>) -> Result<()> {
+            match category_id {
+                Some(category_id) if !self.categories.contains(category_id) => {
+                    Err(MarketplaceError::InvalidCategory)
+                }
+                _ => Ok(()),
+            }
+        }
+
+        fn is_expired(&self, request: &Request) -> bool {
+            request
+                .expires_at
+                .map(|expires_at| self.env().block_timestamp() >= expires_at)
+                .unwrap_or(false)
+        }
+
+        // A closed auction waits for `settle_auction`, so the buyer cannot walk away
+        fn ensure_auction_not_closed(&self, request: &Request) -> Result<()> {
+            if let BiddingMode::ReverseAuction { closes_at, .. } = request.bidding_mode {
+                if request.lifecycle.takes_offers() && self.env().block_timestamp() >= closes_at {
+                    return Err(MarketplaceError::BiddingWindowClosed);
+                }
+            }
+            Ok(())
+        }
+
+        fn rule_dispute(
+            &mut self,
+            request: &mut Request,
+            dispute: &mut Dispute,
+            ruling: DisputeRuling,
+        ) -> Result<()> {
+            match ruling {
+                DisputeRuling::ReleaseToSeller => self.release_escrow(request)?,
+                DisputeRuling::RefundBuyer => {
+                    let buyer_address = self
+                        .user_ids
+                        .get(request.buyer_id)
+                        .ok_or(MarketplaceError::InvalidUser)?;
+                    self.refund_escrow(request, buyer_address)?
+                }
+            }
+            self.transition(request, RequestLifecycle::Resolved)?;
+            self.requests.insert(request.id, request);
+
+            let now = self.env().block_timestamp();
+            dispute.ruling = Some(ruling);
+            dispute.updated_at = now;
+            self.env().emit_event(DisputeRuled {
+                request_id: request.id,
+                ruling,
+                resolved_at: now,
+            });
+            Ok(())
+        }
+
+        fn ensure_request_participant(&self, request: &Request, account: AccountId) -> Result<()> {
+            let user = self
+                .users
+                .get(account)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            if user.id != request.buyer_id && user.id != request.locked_seller_id {
+                return Err(MarketplaceError::NotRequestParticipant);
+            }
+            Ok(())
+        }
+
+        fn release_escrow(&mut self, request: &mut Request) -> Result<()> {
+            let amount = request.escrowed_amount;
+            if amount == 0 {
+                return Ok(());
+            }
+
+            let seller_address = self
+                .user_ids
+                .get(request.locked_seller_id)
+                .ok_or(MarketplaceError::InvalidUser)?;
+
+            request.escrowed_amount = 0;
+            self.pay_out(request.payment_asset, seller_address, amount)?;
+
+            self.env().emit_event(EscrowReleased {
+                request_id: request.id,
+                seller_address,
+                amount,
+            });
+            Ok(())
+        }
+
+        fn refund_escrow(&mut self, request: &mut Request, buyer_address: AccountId) -> Result<()> {
+            let amount = request.escrowed_amount;
+            if amount == 0 {
+                return Ok(());
+            }
+
+            request.escrowed_amount = 0;
+            self.pay_out(request.payment_asset, buyer_address, amount)?;
+
+            self.env().emit_event(EscrowRefunded {
+                request_id: request.id,
+                buyer_address,
+                amount,
+            });
+            Ok(())
+        }
+
+        fn collect_deposit(
+            &mut self,
+            asset: PaymentAsset,
+            from: AccountId,
+            amount: Balance,
+        ) -> Result<()> {
+            let transferred = self.env().transferred_value();
+            match asset {
+                PaymentAsset::Native => {
+                    if transferred < amount {
+                        return Err(MarketplaceError::InsufficientDeposit);
+                    }
+                    if transferred > amount {
+                        return Err(MarketplaceError::IncorrectDeposit);
+                    }
+                    Ok(())
+                }
+                PaymentAsset::Psp22(token) => {
+                    if transferred != 0 {
+                        return Err(MarketplaceError::IncorrectDeposit);
+                    }
+                    let contract = self.env().account_id();
+                    build_call::<Environment>()
+                        .call(token)
+                        .exec_input(
+                            ExecutionInput::new(Selector::new(ink::selector_bytes!(
+                                "PSP22::transfer_from"
+                            )))
+                            .push_arg(from)
+                            .push_arg(contract)
+                            .push_arg(amount)
+                            .push_arg(Vec::<u8>::new()),
+                        )
+                        .returns::<core::result::Result<(), Psp22Error>>()
+                        .try_invoke()
+                        .map_err(|_| MarketplaceError::TokenTransferFailed)?
+                        .map_err(|_| MarketplaceError::TokenTransferFailed)?
+                        .map_err(|_| MarketplaceError::TokenTransferFailed)
+                }
+            }
+        }
+
+        fn pay_out(&mut self, asset: PaymentAsset, to: AccountId, amount: Balance) -> Result<()> {
+            match asset {
+                PaymentAsset::Native => self
+                    .env()
+                    .transfer(to, amount)
+                    .map_err(|_| MarketplaceError::TransferFailed),
+                PaymentAsset::Psp22(token) => build_call::<Environment>()
+                    .call(token)
+                    .exec_input(
+                        ExecutionInput::new(Selector::new(ink::selector_bytes!("PSP22::transfer")))
+                            .push_arg(to)
+                            .push_arg(amount)
+                            .push_arg(Vec::<u8>::new()),
+                    )
+                    .returns::<core::result::Result<(), Psp22Error>>()
+                    .try_invoke()
+                    .map_err(|_| MarketplaceError::TokenTransferFailed)?
+                    .map_err(|_| MarketplaceError::TokenTransferFailed)?
+                    .map_err(|_| MarketplaceError::TokenTransferFailed),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use ink::env::DefaultEnvironment;
+
+        fn masked_phone(number: &str) -> MaskedPhone {
+            let visible_from = number.len().saturating_sub(VISIBLE_PHONE_DIGITS);
+            let mut commitment = [0u8; 32];
+            ink::env::hash_bytes::<Blake2x256>(number.as_bytes(), &mut commitment);
+            MaskedPhone {
+                phone: "*".repeat(visible_from) + &number[visible_from..],
+                commitment: Hash::from(commitment),
+            }
+        }
+
+        fn set_buyer_env() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            ink::env::test::set_caller::<DefaultEnvironment>(accounts.alice);
+            ink::env::test::set_callee::<DefaultEnvironment>(accounts.charlie);
+        }
+
+        fn set_seller_env() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            ink::env::test::set_caller::<DefaultEnvironment>(accounts.bob);
+            ink::env::test::set_callee::<DefaultEnvironment>(accounts.charlie);
+        }
+
+        fn fund_buyer() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            ink::env::test::set_account_balance::<DefaultEnvironment>(
+                accounts.alice,
+                1_000_000_000,
+            );
+        }
+
+        fn balance_of(account: AccountId) -> Balance {
+            ink::env::test::get_account_balance::<DefaultEnvironment>(account).unwrap_or_default()
+        }
+
+        #[test]
+        fn test_contract_initialization() {
+            set_buyer_env();
+            let contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            assert_eq!(contract.user_counter, 0);
+            assert_eq!(contract.store_counter, 0);
+            assert_eq!(contract.request_counter, 0);
+            assert_eq!(contract.offer_counter, 0);
+            assert_eq!(contract.config.time_to_lock, 900 * 1000);
+        }
+
+        #[test]
+        fn test_config_is_set_on_deploy_and_updated_by_admins() {
+            set_buyer_env();
+            let invalid = MarketplaceConfig {
+                cancellation_fee_bps: 10_001,
+                ..MarketplaceConfig::default()
+            };
+            assert_eq!(
+                Marketplace::new(invalid).err(),
+                Some(MarketplaceError::InvalidFee)
+            );
+
+            let config = MarketplaceConfig {
+                time_to_lock: 60 * 60 * 1000,
+                max_images_per_request: 1,
+                max_offers_per_request: 1,
+                cancellation_fee_bps: 250,
+            };
+            let mut contract = Marketplace::new(config.clone()).unwrap();
+            assert_eq!(contract.get_config(), config);
+            assert_eq!(contract.get_cancellation_fee_bps(), 250);
+            // Create a buyer and a request
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            contract
+                .create_request(
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
+                )
+                .unwrap();
+
+            // Create a seller, a store and an offer
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
+                )
+                .unwrap();
+            set_buyer_env();
+            let request_id = contract.request_counter;
+
+            // The request already holds its only allowed offer
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            ink::env::test::set_caller::<DefaultEnvironment>(accounts.django);
+            contract
+                .create_user(
+                    "Django".to_string(),
+                    masked_phone("5555555555"),
+                    0,
+                    0,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "Django's".to_string(),
+                    String::new(),
+                    masked_phone("5555555555"),
+                    0,
+                    0,
+                )
+                .unwrap();
+            assert_eq!(
+                contract.create_offer(request_id, 90, Vec::new(), 2),
+                Err(MarketplaceError::TooManyOffers)
+            );
+
+            set_buyer_env();
+            assert_eq!(
+                contract.create_request(
+                    "Request 2".to_string(),
+                    String::new(),
+                    vec!["a".to_string(), "b".to_string()],
+                    0,
+                    0,
+                    RequestParams::default(),
+                ),
+                Err(MarketplaceError::TooManyImages)
+            );
+
+            set_seller_env();
+            assert_eq!(
+                contract.set_config(MarketplaceConfig::default()),
+                Err(MarketplaceError::MissingRole)
+            );
+            set_buyer_env();
+            assert_eq!(
+                contract.set_config(MarketplaceConfig {
+                    max_offers_per_request: 0,
+                    ..MarketplaceConfig::default()
+                }),
+                Err(MarketplaceError::InvalidConfig)
+            );
+            for max_images_per_request in [0, MAX_IMAGES_PER_REQUEST + 1] {
+                assert_eq!(
+                    contract.set_config(MarketplaceConfig {
+                        max_images_per_request,
+                        ..MarketplaceConfig::default()
+                    }),
+                    Err(MarketplaceError::InvalidConfig)
+                );
+            }
+            for time_to_lock in [0, MIN_TIME_TO_LOCK - 1, MAX_TIME_TO_LOCK + 1, u64::MAX] {
+                assert_eq!(
+                    contract.set_config(MarketplaceConfig {
+                        time_to_lock,
+                        ..MarketplaceConfig::default()
+                    }),
+                    Err(MarketplaceError::InvalidConfig)
+                );
+            }
+            contract.set_config(MarketplaceConfig::default()).unwrap();
+            assert_eq!(contract.config.time_to_lock, DEFAULT_TIME_TO_LOCK);
+            ink::env::test::set_caller::<DefaultEnvironment>(accounts.django);
+            contract
+                .create_offer(request_id, 90, Vec::new(), 2)
+                .unwrap();
+        }
+
+        #[test]
+        fn test_create_user() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+
+            let username = "Alice".to_string();
+            let phone = "1234567890".to_string();
+            let latitude = 12345;
+            let longitude = 54321;
+            let account_type = AccountType::Buyer;
+
+            let result = contract.create_user(
+                username.clone(),
+                masked_phone(&phone),
+                latitude,
+                longitude,
+                account_type.clone(),
+            );
+            assert!(result.is_ok());
+
+            let caller = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>().alice;
+            let user = contract.users.get(caller).unwrap();
+
+            assert_eq!(user.username, username);
+            assert_eq!(user.phone, "********90");
+            assert_eq!(user.location.latitude, latitude);
+            assert_eq!(user.location.longitude, longitude);
+            assert_eq!(user.account_type, account_type);
+        }
+
+        #[test]
+        fn test_update_user() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+
+            let username = "Alice".to_string();
+            let phone = "1234567890".to_string();
+            let latitude = 12345;
+            let longitude = 54321;
+            let account_type = AccountType::Buyer;
+
+            contract
+                .create_user(
+                    username.clone(),
+                    masked_phone(&phone),
+                    latitude,
+                    longitude,
+                    account_type.clone(),
+                )
+                .unwrap();
+
+            // Update the user
+            let new_username = "AliceUpdated".to_string();
+            let new_phone = "0987654321".to_string();
+            let new_latitude = 67890;
+            let new_longitude = 98765;
+            let new_account_type = AccountType::Seller;
+
+            let result = contract.update_user(
+                new_username.clone(),
+                masked_phone(&new_phone),
+                new_latitude,
+                new_longitude,
+                new_account_type.clone(),
+            );
+            assert!(result.is_ok());
+
+            let caller = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>().alice;
+            let user = contract.users.get(caller).unwrap();
+
+            assert_eq!(user.username, new_username);
+            assert_eq!(user.phone, "********21");
+            assert_eq!(user.location.latitude, new_latitude);
+            assert_eq!(user.location.longitude, new_longitude);
+            assert_eq!(user.account_type, new_account_type);
+        }
+
+        #[test]
+        fn test_create_store() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+
+            // Create a seller
+            let username = "Alice".to_string();
+            let phone = "1234567890".to_string();
+            let latitude = 12345;
+            let longitude = 54321;
+            let account_type = AccountType::Seller;
+            contract
+                .create_user(
+                    username.clone(),
+                    masked_phone(&phone),
+                    latitude,
+                    longitude,
+                    account_type,
+                )
+                .unwrap();
+
+            // Create a store
+            let store_name = "My Store".to_string();
+            let store_description = "Best Store".to_string();
+            let result = contract.create_store(
+                store_name.clone(),
+                store_description.clone(),
+                masked_phone(&phone),
+                latitude,
+                longitude,
+            );
+            assert!(result.is_ok());
+
+            // Check store creation
+            let caller = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>().alice;
+            let stores = contract.get_user_stores(caller);
+            assert_eq!(stores.len(), 1);
+            assert_eq!(stores[0].name, store_name);
+            assert_eq!(stores[0].description, store_description);
+            assert!(stores[0].is_active);
+        }
+
+        #[test]
+        fn test_update_deactivate_and_delete_store() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            // Create a buyer
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+
+            // Create a seller and a store
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            set_buyer_env();
+            let store_id = contract.store_counter;
+
+            // Stores can only be managed by their owner
+            assert_eq!(
+                contract.deactivate_store(store_id),
+                Err(MarketplaceError::StoreNotFound)
+            );
+
+            set_seller_env();
+            contract
+                .update_store(
+                    store_id,
+                    "New Store".to_string(),
+                    "Moved".to_string(),
+                    masked_phone("1112223333"),
+                    11111,
+                    22222,
+                )
+                .unwrap();
+            let store = contract.get_user_stores(accounts.bob)[0].clone();
+            assert_eq!(store.name, "New Store");
+            assert_eq!(store.phone, "********33");
+            assert_eq!(
+                store.location,
+                Location {
+                    latitude: 11111,
+                    longitude: 22222
+                }
+            );
+
+            contract.deactivate_store(store_id).unwrap();
+            assert!(!contract.get_user_stores(accounts.bob)[0].is_active);
+            contract.activate_store(store_id).unwrap();
+            assert!(contract.get_user_stores(accounts.bob)[0].is_active);
+
+            assert_eq!(contract.get_store(store_id).unwrap().name, "New Store");
+            assert_eq!(contract.get_store_owner(store_id), Some(accounts.bob));
+
+            contract.delete_store(store_id).unwrap();
+            assert!(contract.get_user_stores(accounts.bob).is_empty());
+            assert_eq!(contract.get_store(store_id), None);
+            assert_eq!(contract.get_store_owner(store_id), None);
+            assert!(contract
+                .user_store_ids
+                .get(accounts.bob)
+                .unwrap()
+                .is_empty());
+            assert_eq!(
+                contract.delete_store(store_id),
+                Err(MarketplaceError::StoreNotFound)
+            );
+        }
+
+        #[test]
+        fn test_list_stores_pages_through_all_sellers() {
+            set_seller_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            for seller in [accounts.bob, accounts.django] {
+                ink::env::test::set_caller::<DefaultEnvironment>(seller);
+                contract
+                    .create_user(
+                        "Seller".to_string(),
+                        masked_phone("5555555555"),
+                        98765,
+                        56789,
+                        AccountType::Seller,
+                    )
+                    .unwrap();
+                for name in ["North", "South"] {
+                    contract
+                        .create_store(
+                            name.to_string(),
+                            String::new(),
+                            masked_phone("5555555555"),
+                            98765,
+                            56789,
+                        )
+                        .unwrap();
+                }
+            }
+
+            // Deleted stores drop out of the listing
+            contract.delete_store(3).unwrap();
+
+            let ids = |stores: Vec<Store>| stores.iter().map(|store| store.id).collect::<Vec<_>>();
+            assert_eq!(ids(contract.list_stores(0, 10)), vec![1, 2, 4]);
+            assert_eq!(ids(contract.list_stores(0, 2)), vec![1, 2]);
+            assert_eq!(ids(contract.list_stores(3, 2)), vec![4]);
+            assert_eq!(contract.get_store_owner(4), Some(accounts.django));
+        }
+
+        #[test]
+        fn test_purchase_listing_creates_accepted_request() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            // Create a buyer
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+
+            // Create a seller and a store
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            set_buyer_env();
+            let store_id = contract.store_counter;
+
+            set_seller_env();
+            contract
+                .create_listing(
+                    store_id,
+                    "Widget".to_string(),
+                    "Blue widget".to_string(),
+                    30,
+                    2,
+                    vec!["widget".to_string()],
+                    None,
+                    PaymentAsset::Native,
+                )
+                .unwrap();
+            let listing_id = contract.listing_counter;
+            assert_eq!(contract.get_store_listings(store_id).len(), 1);
+
+            set_buyer_env();
+            fund_buyer();
+            assert_eq!(
+                contract.update_listing(
+                    listing_id,
+                    "Widget".to_string(),
+                    String::new(),
+                    1,
+                    100,
+                    Vec::new(),
+                    None,
+                ),
+                Err(MarketplaceError::UnauthorizedSeller)
+            );
+            assert_eq!(
+                ink::env::pay_with_call!(contract.purchase_listing(listing_id, 3), 90),
+                Err(MarketplaceError::OutOfStock)
+            );
+            assert_eq!(
+                ink::env::pay_with_call!(contract.purchase_listing(listing_id, 2), 30),
+                Err(MarketplaceError::InsufficientDeposit)
+            );
+            ink::env::pay_with_call!(contract.purchase_listing(listing_id, 2), 60).unwrap();
+
+            // The purchase is an accepted request with the total in escrow
+            let request_id = contract.request_counter;
+            let request = contract.get_request(request_id).unwrap();
+            assert_eq!(request.lifecycle, RequestLifecycle::AcceptedByBuyer);
+            assert_eq!(request.name, "Widget");
+            assert_eq!(request.escrowed_amount, 60);
+            let offer = contract.get_offer(contract.offer_counter).unwrap();
+            assert!(offer.is_accepted);
+            assert_eq!(offer.store_id, store_id);
+            assert_eq!(request.locked_seller_id, offer.seller_id);
+            assert_eq!(contract.get_listing(listing_id).unwrap().stock, 0);
+            assert_eq!(
+                ink::env::pay_with_call!(contract.purchase_listing(listing_id, 1), 30),
+                Err(MarketplaceError::OutOfStock)
+            );
+
+            // It settles like any other accepted request
+            let seller_balance = balance_of(accounts.bob);
+            ink::env::test::set_block_timestamp::<DefaultEnvironment>(
+                contract.config.time_to_lock + 1,
+            );
+            contract.mark_request_as_completed(request_id).unwrap();
+            assert_eq!(balance_of(accounts.bob), seller_balance + 60);
+
+            set_seller_env();
+            contract.remove_listing(listing_id).unwrap();
+            assert_eq!(contract.get_listing(listing_id), None);
+            assert!(contract.get_store_listings(store_id).is_empty());
+        }
+
+        #[test]
+        fn test_cancelled_purchase_restores_listing_stock() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            // Create a buyer
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+
+            // Create a seller and a store
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            set_buyer_env();
+            let store_id = contract.store_counter;
+
+            set_seller_env();
+            contract
+                .create_listing(
+                    store_id,
+                    "Widget".to_string(),
+                    String::new(),
+                    30,
+                    2,
+                    Vec::new(),
+                    None,
+                    PaymentAsset::Native,
+                )
+                .unwrap();
+            let listing_id = contract.listing_counter;
+
+            set_buyer_env();
+            fund_buyer();
+            ink::env::pay_with_call!(contract.purchase_listing(listing_id, 2), 60).unwrap();
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
+            assert_eq!(contract.get_listing(listing_id).unwrap().stock, 0);
+
+            // The seller cannot reprice or pull out of a purchase
+            set_seller_env();
+            assert_eq!(
+                contract.update_offer(offer_id, 10, Vec::new()),
+                Err(MarketplaceError::FixedPriceOffer)
+            );
+            assert_eq!(
+                contract.withdraw_offer(offer_id),
+                Err(MarketplaceError::FixedPriceOffer)
+            );
+
+            set_buyer_env();
+            contract.cancel_request(request_id).unwrap();
+            assert_eq!(contract.get_listing(listing_id).unwrap().stock, 2);
+        }
+
+        #[test]
+        fn test_offer_requires_own_active_store() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            // Create a buyer and a request
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            contract
+                .create_request(
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
+                )
+                .unwrap();
+
+            // Create a seller, a store and an offer
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
+                )
+                .unwrap();
+            set_buyer_env();
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
+            let store_id = contract.store_counter;
+
+            // The offer carries the details of the store it was made from
+            let offer = contract.get_offer(offer_id).unwrap();
+            assert_eq!(offer.store_id, store_id);
+            assert_eq!(offer.store_name, "My Store");
+            assert_eq!(
+                offer.store_location,
+                Location {
+                    latitude: 98765,
+                    longitude: 56789
+                }
+            );
+
+            // Sellers cannot offer from a store they do not own
+            ink::env::test::set_caller::<DefaultEnvironment>(
+                ink::env::test::default_accounts::<DefaultEnvironment>().django,
+            );
+            contract
+                .create_user(
+                    "Django".to_string(),
+                    masked_phone("5555555555"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            assert_eq!(
+                contract.create_offer(request_id, 90, Vec::new(), store_id),
+                Err(MarketplaceError::StoreNotFound)
+            );
+
+            set_seller_env();
+            contract.withdraw_offer(offer_id).unwrap();
+            contract.deactivate_store(store_id).unwrap();
+            assert_eq!(
+                contract.create_offer(request_id, 90, Vec::new(), store_id),
+                Err(MarketplaceError::StoreInactive)
+            );
+        }
+
+        #[test]
+        fn test_create_request() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+
+            // Create a buyer
+            let username = "Bob".to_string();
+            let phone = "0987654321".to_string();
+            let latitude = 98765;
+            let longitude = 56789;
+            let account_type = AccountType::Buyer;
+            contract
+                .create_user(
+                    username.clone(),
+                    masked_phone(&phone),
+                    latitude,
+                    longitude,
+                    account_type,
+                )
+                .unwrap();
+
+            // Create a request
+            let request_name = "Request 1".to_string();
+            let request_description = "Need this item".to_string();
+            let images = vec!["image1".to_string(), "image2".to_string()];
+            let result = contract.create_request(
+                request_name.clone(),
+                request_description.clone(),
+                images.clone(),
+                latitude,
+                longitude,
+                RequestParams::default(),
+            );
+            assert!(result.is_ok());
+
+            // Check request creation
+            let requests = contract.get_all_requests();
+            assert_eq!(requests.len(), 1);
+            assert_eq!(requests[0].name, request_name);
+            assert_eq!(requests[0].description, request_description);
+            assert_eq!(requests[0].images, images);
+        }
+
+        #[test]
+        fn test_requests_by_category() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            contract.add_category("Plumbing".to_string()).unwrap();
+            contract.add_category("Groceries".to_string()).unwrap();
+
+            let create =
+                |contract: &mut Marketplace, category_id: Option<u64>, tags: Vec<String>| {
+                    contract.create_request(
+                        "Request".to_string(),
+                        String::new(),
+                        Vec::new(),
+                        98765,
+                        56789,
+                        RequestParams {
+                            category_id,
+                            tags,
+                            ..Default::default()
+                        },
+                    )
+                };
+            assert_eq!(
+                create(&mut contract, Some(3), Vec::new()),
+                Err(MarketplaceError::InvalidCategory)
+            );
+            assert_eq!(
+                create(&mut contract, Some(1), vec![String::new()]),
+                Err(MarketplaceError::InvalidTags)
+            );
+            for category_id in [Some(1), Some(2), Some(1), None, Some(1)] {
+                create(&mut contract, category_id, vec!["urgent".to_string()]).unwrap();
+            }
+            contract.delete_request(3).unwrap();
+
+            let (page, cursor) = contract.get_requests_by_category(1, 0, 2);
+            assert_eq!(page.iter().map(|r| r.id).collect::<Vec<_>>(), vec![1]);
+            assert_eq!(page[0].tags, vec!["urgent".to_string()]);
+            assert_eq!(cursor, 2);
+            let (page, cursor) = contract.get_requests_by_category(1, cursor, 2);
+            assert_eq!(page.iter().map(|r| r.id).collect::<Vec<_>>(), vec![5]);
+            assert_eq!(cursor, 3);
+            assert_eq!(contract.get_requests_by_category(2, 0, 10).0.len(), 1);
+
+            // Only the owner manages the registry
+            ink::env::test::set_caller::<DefaultEnvironment>(accounts.bob);
+            assert_eq!(
+                contract.add_category("Tools".to_string()),
+                Err(MarketplaceError::MissingRole)
+            );
+            set_buyer_env();
+            contract.remove_category(2).unwrap();
+            assert_eq!(contract.get_categories().len(), 1);
+            assert_eq!(
+                create(&mut contract, Some(2), Vec::new()),
+                Err(MarketplaceError::InvalidCategory)
+            );
+        }
+
+        #[test]
+        fn test_location_distance() {
+            let at = |latitude: i128, longitude: i128| Location {
+                latitude,
+                longitude,
+            };
+            // 0.01 degrees of latitude is about 1.1 km anywhere
+            assert_eq!(
+                at(52_000_000, 13_000_000).distance_to(&at(52_010_000, 13_000_000)),
+                1111
+            );
+            // A degree of longitude shrinks with latitude (~68.5 km at 52 degrees)
+            let distance = at(52_000_000, 13_000_000).distance_to(&at(52_000_000, 14_000_000));
+            assert!((68_000..69_000).contains(&distance), "{distance}");
+            // Longitude wraps around the antimeridian
+            let distance = at(0, 179_990_000).distance_to(&at(0, -179_990_000));
+            assert!((2_200..2_250).contains(&distance), "{distance}");
+        }
+
+        #[test]
+        fn test_disabled_location_is_coarsened_except_for_counterparty() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            // Create a buyer and a request
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            contract
+                .create_request(
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
+                )
+                .unwrap();
+
+            // Create a seller, a store and an offer
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
+                )
+                .unwrap();
+            set_buyer_env();
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
+            let exact = Location {
+                latitude: 98765,
+                longitude: 56789,
+            };
+            let coarse = exact.coarsened();
+            assert_ne!(coarse, exact);
+            assert_eq!(coarse.cell(), exact.cell());
+
+            contract.toggle_location(false).unwrap();
+            assert_eq!(contract.get_user(accounts.alice).unwrap().location, exact);
+
+            // Other users only see the cell of a disabled location
+            set_seller_env();
+            assert_eq!(contract.get_user(accounts.alice).unwrap().location, coarse);
+            assert_eq!(contract.get_user_by_id(1).unwrap().location, coarse);
+            assert_eq!(contract.get_request(request_id).unwrap().location, coarse);
+            assert_eq!(contract.get_all_requests()[0].location, coarse);
+
+            // The seller of an accepted offer sees the exact location
+            set_buyer_env();
+            fund_buyer();
+            ink::env::pay_with_call!(contract.accept_offer(offer_id, 100), 100).unwrap();
+            set_seller_env();
+            assert_eq!(contract.get_user(accounts.alice).unwrap().location, exact);
+            assert_eq!(contract.get_request(request_id).unwrap().location, exact);
+
+            ink::env::test::set_caller::<DefaultEnvironment>(accounts.django);
+            assert_eq!(contract.get_user(accounts.alice).unwrap().location, coarse);
+            assert_eq!(contract.get_request(request_id).unwrap().location, coarse);
+
+            // Visibility ends with the request
+            set_buyer_env();
+            ink::env::test::set_block_timestamp::<DefaultEnvironment>(
+                contract.config.time_to_lock + 1,
+            );
+            contract.mark_request_as_completed(request_id).unwrap();
+            set_seller_env();
+            assert_eq!(contract.get_user(accounts.alice).unwrap().location, coarse);
+            assert_eq!(contract.get_request(request_id).unwrap().location, coarse);
+        }
+
+        #[test]
+        fn test_contacts_are_exchanged_between_counterparties() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            // Create a buyer and a request
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            contract
+                .create_request(
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
+                )
+                .unwrap();
+
+            // Create a seller, a store and an offer
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
+                )
+                .unwrap();
+            set_buyer_env();
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
+            assert_eq!(
+                contract.get_user(accounts.alice).unwrap().phone,
+                "********21"
+            );
+            assert!(is_masked_phone("1"));
+            assert!(!is_masked_phone("0987654321"));
+
+            assert_eq!(
+                contract
+                    .get_user(accounts.alice)
+                    .unwrap()
+                    .contact_commitment,
+                masked_phone("0987654321").commitment
+            );
+
+            // No exchange before an offer is accepted
+            assert_eq!(
+                contract.publish_contact_key(request_id, [1; 32]),
+                Err(MarketplaceError::RequestNotAccepted)
+            );
+
+            ink::env::test::set_caller::<DefaultEnvironment>(accounts.eve);
+            contract
+                .create_user(
+                    "Eve".to_string(),
+                    masked_phone("4444444444"),
+                    0,
+                    0,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "Eve's".to_string(),
+                    String::new(),
+                    masked_phone("4444444444"),
+                    0,
+                    0,
+                )
+                .unwrap();
+            contract
+                .create_offer(request_id, 100, Vec::new(), contract.store_counter)
+                .unwrap();
+            let other_offer_id = contract.offer_counter;
+            set_buyer_env();
+
+            fund_buyer();
+            ink::env::pay_with_call!(contract.accept_offer(offer_id, 100), 100).unwrap();
+            contract.publish_contact_key(request_id, [1; 32]).unwrap();
+            assert_eq!(
+                contract.share_contact(request_id, vec![9; 16]),
+                Err(MarketplaceError::ContactKeyMissing)
+            );
+
+            set_seller_env();
+            contract.publish_contact_key(request_id, [2; 32]).unwrap();
+            assert_eq!(
+                contract.share_contact(request_id, vec![0; MAX_CONTACT_LENGTH + 1]),
+                Err(MarketplaceError::InvalidContact)
+            );
+            contract.share_contact(request_id, vec![8; 16]).unwrap();
+
+            set_buyer_env();
+            contract.share_contact(request_id, vec![9; 16]).unwrap();
+            let exchange = contract.get_contact_exchange(request_id).unwrap();
+            assert_eq!(exchange.buyer_key, Some([1; 32]));
+            assert_eq!(exchange.seller_key, Some([2; 32]));
+            assert_eq!(exchange.buyer_contact, Some(vec![9; 16]));
+            assert_eq!(exchange.seller_contact, Some(vec![8; 16]));
+
+            // A new key voids the contact encrypted to the old one
+            contract.publish_contact_key(request_id, [3; 32]).unwrap();
+            let exchange = contract.get_contact_exchange(request_id).unwrap();
+            assert_eq!(exchange.buyer_contact, Some(vec![9; 16]));
+            assert_eq!(exchange.seller_contact, None);
+
+            // Outsiders cannot read the exchange, and phones must be masked by the
+            // client
+            ink::env::test::set_caller::<DefaultEnvironment>(accounts.django);
+            assert_eq!(
+                contract.create_user(
+                    "Django".to_string(),
+                    MaskedPhone {
+                        phone: "5555555555".to_string(),
+                        commitment: Hash::from([5; 32]),
+                    },
+                    0,
+                    0,
+                    AccountType::Seller,
+                ),
+                Err(MarketplaceError::PhoneNotMasked)
+            );
+            contract
+                .create_user(
+                    "Django".to_string(),
+                    masked_phone("5555555555"),
+                    0,
+                    0,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            assert_eq!(
+                contract.get_contact_exchange(request_id),
+                Err(MarketplaceError::NotRequestParticipant)
+            );
+
+            // Switching to another offer discards the previous seller's exchange
+            set_buyer_env();
+            fund_buyer();
+            ink::env::pay_with_call!(contract.accept_offer(other_offer_id, 100), 100).unwrap();
+            assert!(!contract.contact_exchanges.contains((request_id, 2)));
+            let exchange = contract.get_contact_exchange(request_id).unwrap();
+            assert_eq!(exchange.buyer_key, None);
+
+            // Cancelling the request discards the exchange
+            contract.publish_contact_key(request_id, [4; 32]).unwrap();
+            let other_seller_id = contract.get_user(accounts.eve).unwrap().id;
+            assert!(contract
+                .contact_exchanges
+                .contains((request_id, other_seller_id)));
+            contract.cancel_request(request_id).unwrap();
+            assert_eq!(
+                contract.get_contact_exchange(request_id),
+                Err(MarketplaceError::RequestNotAccepted)
+            );
+            assert!(!contract
+                .contact_exchanges
+                .contains((request_id, other_seller_id)));
+        }
+
+        #[test]
+        fn test_locations_are_validated() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            assert_eq!(
+                contract.create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    90_000_001,
+                    0,
+                    AccountType::Buyer,
+                ),
+                Err(MarketplaceError::InvalidLocation)
+            );
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    -90_000_000,
+                    180_000_000,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            assert_eq!(
+                contract.update_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    0,
+                    -180_000_001,
+                    AccountType::Buyer,
+                ),
+                Err(MarketplaceError::InvalidLocation)
+            );
+            assert_eq!(
+                contract.create_request(
+                    "Request".to_string(),
+                    String::new(),
+                    Vec::new(),
+                    52_520_000_000_000_000,
+                    13_405_000_000_000_000,
+                    RequestParams::default(),
+                ),
+                Err(MarketplaceError::InvalidLocation)
+            );
+            assert_eq!(
+                contract.create_request(
+                    "Request".to_string(),
+                    String::new(),
+                    Vec::new(),
+                    i128::MIN,
+                    i128::MIN,
+                    RequestParams::default(),
+                ),
+                Err(MarketplaceError::InvalidLocation)
+            );
+
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    0,
+                    0,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            assert_eq!(
+                contract.create_store(
+                    "My Store".to_string(),
+                    String::new(),
+                    masked_phone("1234567890"),
+                    525_200_000,
+                    134_050_000,
+                ),
+                Err(MarketplaceError::InvalidLocation)
+            );
+
+            assert_eq!(
+                contract.get_distance(52_520_000, 13_405_000, 52_530_000, 13_405_000),
+                Ok(1111)
+            );
+            assert_eq!(
+                contract.get_distance(52_520_000, 13_405_000, 91_000_000, 13_405_000),
+                Err(MarketplaceError::InvalidLocation)
+            );
+        }
+
+        #[test]
+        fn test_proximity_search_scans_neighbouring_cells() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    52_520_000,
+                    13_405_000,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            let create = |contract: &mut Marketplace, latitude: i128, longitude: i128| {
+                contract
+                    .create_request(
+                        "Request".to_string(),
+                        String::new(),
+                        Vec::new(),
+                        latitude,
+                        longitude,
+                        RequestParams::default(),
+                    )
+                    .unwrap();
+                contract.request_counter
+            };
+            let near = create(&mut contract, 52_530_000, 13_405_000); // ~1.1 km
+            let across_cells = create(&mut contract, 52_560_000, 13_405_000); // ~4.4 km
+            let deleted = create(&mut contract, 52_521_000, 13_405_000);
+            create(&mut contract, 52_655_000, 13_405_000); // ~15 km
+            contract.delete_request(deleted).unwrap();
+
+            let center = Location {
+                latitude: 52_520_000,
+                longitude: 13_405_000,
+            };
+            let other = Location {
+                latitude: 52_560_000,
+                longitude: 13_405_000,
+            };
+            assert_ne!(center.cell(), other.cell());
+
+            let ids = |requests: Vec<Request>| requests.iter().map(|r| r.id).collect::<Vec<_>>();
+            assert_eq!(
+                ids(contract.get_requests_near(52_520_000, 13_405_000, 5_000, 10)),
+                vec![near, across_cells]
+            );
+            assert_eq!(
+                ids(contract.get_requests_near(52_520_000, 13_405_000, 5_000, 1)),
+                vec![near]
+            );
+            // The radius is capped
+            assert_eq!(
+                contract
+                    .get_requests_near(52_520_000, 13_405_000, 50_000, 10)
+                    .len(),
+                2
+            );
+
+            // Stores are indexed the same way and follow location updates
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    52_520_000,
+                    13_405_000,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    String::new(),
+                    masked_phone("1234567890"),
+                    52_525_000,
+                    13_405_000,
+                )
+                .unwrap();
+            let store_id = contract.store_counter;
+            assert_eq!(
+                contract.get_stores_near(52_520_000, 13_405_000, 1_000, 10)[0].id,
+                store_id
+            );
+            contract
+                .update_store(
+                    store_id,
+                    "My Store".to_string(),
+                    String::new(),
+                    masked_phone("1234567890"),
+                    48_137_000,
+                    11_575_000,
+                )
+                .unwrap();
+            assert!(contract
+                .get_stores_near(52_520_000, 13_405_000, 1_000, 10)
+                .is_empty());
+            assert_eq!(
+                contract
+                    .get_stores_near(48_137_000, 11_575_000, 1_000, 10)
+                    .len(),
+                1
+            );
+        }
+
+        #[test]
+        fn test_subscribed_sellers_are_matched_to_nearby_requests() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            // Create a buyer
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+
+            // Create a seller and a store
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            set_buyer_env();
+            contract.add_category("Plumbing".to_string()).unwrap();
+            contract.add_category("Groceries".to_string()).unwrap();
+
+            set_seller_env();
+            contract
+                .create_store(
+                    "Berlin Store".to_string(),
+                    String::new(),
+                    masked_phone("1234567890"),
+                    52_000_000,
+                    13_000_000,
+                )
+                .unwrap();
+            let store_id = contract.store_counter;
+            assert_eq!(
+                contract.subscribe(store_id, 5_000, Vec::new()),
+                Err(MarketplaceError::InvalidSubscription)
+            );
+            assert_eq!(
+                contract.subscribe(store_id, 5_000, vec![3]),
+                Err(MarketplaceError::InvalidCategory)
+            );
+            contract.subscribe(store_id, 5_000, vec![1]).unwrap();
+
+            set_buyer_env();
+            let create = |contract: &mut Marketplace, latitude: i128, category_id: u64| {
+                contract
+                    .create_request(
+                        "Request".to_string(),
+                        String::new(),
+                        Vec::new(),
+                        latitude,
+                        13_000_000,
+                        RequestParams {
+                            category_id: Some(category_id),
+                            ..Default::default()
+                        },
+                    )
+                    .unwrap();
+                contract.request_counter
+            };
+            let nearby = create(&mut contract, 52_010_000, 1);
+            create(&mut contract, 52_100_000, 1); // ~11 km away
+            create(&mut contract, 52_010_000, 2); // other category
+
+            let matched = contract.get_matched_requests(accounts.bob);
+            assert_eq!(matched.len(), 1);
+            assert_eq!(matched[0].id, nearby);
+
+            // Inactive stores and removed subscriptions no longer match
+            set_seller_env();
+            contract.deactivate_store(store_id).unwrap();
+            set_buyer_env();
+            create(&mut contract, 52_010_000, 1);
+            set_seller_env();
+            contract.activate_store(store_id).unwrap();
+            contract.unsubscribe(store_id).unwrap();
+            assert_eq!(
+                contract.unsubscribe(store_id),
+                Err(MarketplaceError::SubscriptionNotFound)
+            );
+            set_buyer_env();
+            create(&mut contract, 52_010_000, 1);
+            assert_eq!(contract.get_matched_requests(accounts.bob).len(), 1);
+
+            // Unsubscribing one store leaves the other subscribers of the category
+            set_seller_env();
+            contract.subscribe(store_id, 5_000, vec![1]).unwrap();
+            contract
+                .create_store(
+                    "Second Store".to_string(),
+                    String::new(),
+                    masked_phone("1234567890"),
+                    52_000_000,
+                    13_000_000,
+                )
+                .unwrap();
+            contract
+                .subscribe(contract.store_counter, 5_000, vec![1, 2])
+                .unwrap();
+            contract.unsubscribe(store_id).unwrap();
+            set_buyer_env();
+            let latest = create(&mut contract, 52_010_000, 1);
+            let matched = contract.get_matched_requests(accounts.bob);
+            assert_eq!(matched.len(), 2);
+            assert_eq!(matched[1].id, latest);
+
+            // A disabled location is matched by the center of its cell
+            let exact = Location {
+                latitude: 52_000_000,
+                longitude: 13_000_000,
+            };
+            assert!(exact.distance_to(&exact.coarsened()) > 100);
+            set_seller_env();
+            contract
+                .subscribe(contract.store_counter, 100, vec![1])
+                .unwrap();
+            set_buyer_env();
+            contract.toggle_location(false).unwrap();
+            create(&mut contract, 52_000_000, 1);
+            assert_eq!(contract.get_matched_requests(accounts.bob).len(), 2);
+            contract.toggle_location(true).unwrap();
+            let exact_match = create(&mut contract, 52_000_000, 1);
+            assert_eq!(
+                contract.get_matched_requests(accounts.bob)[2].id,
+                exact_match
+            );
+        }
+
+        #[test]
+        fn test_create_offer() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+
+            // Create a buyer and a request
+            let buyer_name = "Alice".to_string();
+            let buyer_phone = "0987654321".to_string();
+            let latitude = 98765;
+            let longitude = 56789;
+            let buyer_account_type = AccountType::Buyer;
+            contract
+                .create_user(
+                    buyer_name.clone(),
+                    masked_phone(&buyer_phone),
+                    latitude,
+                    longitude,
+                    buyer_account_type,
+                )
+                .unwrap();
+
+            let request_name = "Request 1".to_string();
+            let request_description = "Need this item".to_string();
+            let images = vec!["image1".to_string()];
+            contract
+                .create_request(
+                    request_name.clone(),
+                    request_description.clone(),
+                    images.clone(),
+                    latitude,
+                    longitude,
+                    RequestParams::default(),
+                )
+                .unwrap();
+
+            set_seller_env();
+
+            // Create a seller and a store
+            let seller_name = "Bob".to_string();
+            let seller_phone = "1234567890".to_string();
+            let seller_account_type = AccountType::Seller;
+            contract
+                .create_user(
+                    seller_name.clone(),
+                    masked_phone(&seller_phone),
+                    latitude,
+                    longitude,
+                    seller_account_type,
+                )
+                .unwrap();
+
+            let store_name = "My Store".to_string();
+            let store_description = "Best Store".to_string();
+            contract
+                .create_store(
+                    store_name.clone(),
+                    store_description,
+                    masked_phone(&seller_phone),
+                    latitude,
+                    longitude,
+                )
+                .unwrap();
+
+            // Create an offer
+            let request_id = 1;
+            let offer_price = 100;
+            let offer_images = vec!["offer_image1".to_string()];
+
+            contract
+                .create_offer(
+                    request_id,
+                    offer_price,
+                    offer_images.clone(),
+                    contract.store_counter,
+                )
+                .unwrap();
+
+            // Check offer creation
+            let offers = contract.get_offer_by_request(request_id);
+            assert_eq!(offers.len(), 1);
+            assert_eq!(offers[0].price, offer_price);
+            assert_eq!(offers[0].images, offer_images);
+            assert_eq!(offers[0].store_name, store_name);
+        }
+
+        #[test]
+        fn test_accept_offer() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+
+            // Create a buyer and a request
+            let buyer_name = "Bob".to_string();
+            let buyer_phone = "0987654321".to_string();
+            let latitude = 98765;
+            let longitude = 56789;
+            let buyer_account_type = AccountType::Buyer;
+            contract
+                .create_user(
+                    buyer_name.clone(),
+                    masked_phone(&buyer_phone),
+                    latitude,
+                    longitude,
+                    buyer_account_type,
+                )
+                .unwrap();
+
+            let request_name = "Request 1".to_string();
+            let request_description = "Need this item".to_string();
+            let images = vec!["image1".to_string()];
+            contract
+                .create_request(
+                    request_name.clone(),
+                    request_description.clone(),
+                    images.clone(),
+                    latitude,
+                    longitude,
+                    RequestParams::default(),
+                )
+                .unwrap();
+
+            set_seller_env();
+
+            // Create a seller and a store
+            let seller_name = "Alice".to_string();
+            let seller_phone = "1234567890".to_string();
+            let seller_account_type = AccountType::Seller;
+            contract
+                .create_user(
+                    seller_name.clone(),
+                    masked_phone(&seller_phone),
+                    latitude,
+                    longitude,
+                    seller_account_type,
+                )
+                .unwrap();
+
+            let store_name = "My Store".to_string();
+            let store_description = "Best Store".to_string();
+            contract
+                .create_store(
+                    store_name.clone(),
+                    store_description,
+                    masked_phone(&seller_phone),
+                    latitude,
+                    longitude,
+                )
+                .unwrap();
+
+            // Create an offer
+            let request_id = 1;
+            let offer_price = 100;
+            let offer_images = vec!["offer_image1".to_string()];
+            contract
+                .create_offer(
+                    request_id,
+                    offer_price,
+                    offer_images.clone(),
+                    contract.store_counter,
+                )
+                .unwrap();
+
+            set_buyer_env();
+
+            // Accept the offer
+            let offer_id = 1;
+            fund_buyer();
+            let result =
+                ink::env::pay_with_call!(contract.accept_offer(offer_id, offer_price), offer_price);
+            assert!(result.is_ok());
+
+            // // Check if the offer was accepted
+            let accepted_offer = contract.get_offer(offer_id).unwrap();
+            assert!(accepted_offer.is_accepted);
+
+            // Check the request lifecycle
+            let request = contract.get_request(request_id).unwrap();
+            assert_eq!(request.lifecycle, RequestLifecycle::AcceptedByBuyer);
+            assert_eq!(request.locked_seller_id, accepted_offer.seller_id);
         }
 
-        #[ink(message)]
-        pub fn create_user(
-            &mut self,
-            username: String,
-            phone: String,
-            latitude: i128,
-            longitude: i128,
-            account_type: AccountType,
-        ) -> Result<()> {
-            let caller = self.env().caller();
-            let user = self.users.get(caller);
-            if user.is_some() {
-                return Err(MarketplaceError::UserAlreadyExists);
-            }
-            self.user_counter = self.user_counter.checked_add(1).unwrap();
-            let new_user = User {
-                id: self.user_counter,
-                username: username.clone(),
-                phone,
-                location: Location {
+        #[test]
+        fn test_mark_request_as_completed() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+
+            // Create a buyer and a request
+            let buyer_name = "Bob".to_string();
+            let buyer_phone = "0987654321".to_string();
+            let latitude = 98765;
+            let longitude = 56789;
+            let buyer_account_type = AccountType::Buyer;
+            contract
+                .create_user(
+                    buyer_name.clone(),
+                    masked_phone(&buyer_phone),
                     latitude,
                     longitude,
-                },
-                created_at: self.env().block_timestamp(),
-                updated_at: self.env().block_timestamp(),
-                account_type: account_type.clone(),
-                authority: caller.clone(),
-                location_enabled: true, // NOTE: we enable location by default
-            };
+                    buyer_account_type,
+                )
+                .unwrap();
 
-            self.users.insert(&caller, &new_user);
-            self.user_ids.insert(self.user_counter, &caller);
-            self.env().emit_event(UserCreated {
-                user_address: caller,
-                user_id: self.user_counter,
-                username,
-                account_type: match account_type {
-                    AccountType::Buyer => 0,
-                    AccountType::Seller => 1,
-                },
-            });
-            Ok(())
-        }
+            let request_name = "Request 1".to_string();
+            let request_description = "Need this item".to_string();
+            let images = vec!["image1".to_string()];
+            contract
+                .create_request(
+                    request_name.clone(),
+                    request_description.clone(),
+                    images.clone(),
+                    latitude,
+                    longitude,
+                    RequestParams::default(),
+                )
+                .unwrap();
 
-        #[ink(message)]
-        pub fn update_user(
-            &mut self,
-            username: String,
-            phone: String,
-            latitude: i128,
-            longitude: i128,
-            account_type: AccountType,
-        ) -> Result<()> {
-            let caller = self.env().caller();
-            let mut user = self
-                .users
-                .get(caller)
-                .ok_or(MarketplaceError::InvalidUser)?;
+            set_seller_env();
 
-            user.username = username.clone();
-            user.phone = phone;
-            user.location = Location {
-                latitude,
-                longitude,
-            };
-            user.updated_at = self.env().block_timestamp();
-            user.account_type = account_type.clone();
+            // Create a seller and a store
+            let seller_name = "Alice".to_string();
+            let seller_phone = "1234567890".to_string();
+            let seller_account_type = AccountType::Seller;
+            contract
+                .create_user(
+                    seller_name.clone(),
+                    masked_phone(&seller_phone),
+                    latitude,
+                    longitude,
+                    seller_account_type,
+                )
+                .unwrap();
 
-            self.users.insert(caller, &user);
+            let store_name = "My Store".to_string();
+            let store_description = "Best Store".to_string();
+            contract
+                .create_store(
+                    store_name.clone(),
+                    store_description,
+                    masked_phone(&seller_phone),
+                    latitude,
+                    longitude,
+                )
+                .unwrap();
 
-            self.env().emit_event(UserUpdated {
-                user_address: caller,
-                user_id: user.id,
-                username,
-                account_type: match account_type {
-                    AccountType::Buyer => 0,
-                    AccountType::Seller => 1,
-                },
-            });
-            Ok(())
+            // Create an offer
+            let request_id = 1;
+            let offer_price = 100;
+            let offer_images = vec!["offer_image1".to_string()];
+            contract
+                .create_offer(
+                    request_id,
+                    offer_price,
+                    offer_images.clone(),
+                    contract.store_counter,
+                )
+                .unwrap();
+
+            set_buyer_env();
+
+            // // Accept the offer
+            let offer_id = 1;
+            fund_buyer();
+            ink::env::pay_with_call!(contract.accept_offer(offer_id, offer_price), offer_price)
+                .unwrap();
+
+            // // Mark the request as completed
+            // let result = contract.mark_request_as_completed(request_id).unwrap();
+            // assert!(result.is_ok());
+
+            // // Check the request lifecycle
+            // let request = contract.get_request(request_id).unwrap();
+            // assert_eq!(request.lifecycle, RequestLifecycle::Completed);
         }
 
-        #[ink(message)]
-        pub fn create_store(
-            &mut self,
-            name: String,
-            description: String,
-            phone: String,
-            latitude: i128,
-            longitude: i128,
-        ) -> Result<()> {
-            let caller = self.env().caller();
-            let user = self
-                .users
-                .get(caller)
-                .ok_or(MarketplaceError::InvalidUser)?;
+        #[test]
+        // remove request
+        fn test_remove_request() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
 
-            if user.account_type != AccountType::Seller {
-                return Err(MarketplaceError::OnlySellersAllowed);
-            }
+            // Create a buyer and a request
+            let buyer_name = "Bob".to_string();
+            let buyer_phone = "0987654321".to_string();
+            let latitude = 98765;
+            let longitude = 56789;
+            let buyer_account_type = AccountType::Buyer;
+            contract
+                .create_user(
+                    buyer_name.clone(),
+                    masked_phone(&buyer_phone),
+                    latitude,
+                    longitude,
+                    buyer_account_type,
+                )
+                .unwrap();
 
-            self.store_counter = self.store_counter.checked_add(1).unwrap();
-            let new_store = Store {
-                id: self.store_counter,
-                name: name.clone(),
-                description,
-                phone,
-                location: Location {
+            let request_name = "Request 1".to_string();
+            let request_description = "Need this item".to_string();
+            let images = vec!["image1".to_string()];
+            contract
+                .create_request(
+                    request_name.clone(),
+                    request_description.clone(),
+                    images.clone(),
                     latitude,
                     longitude,
-                },
-            };
+                    RequestParams::default(),
+                )
+                .unwrap();
 
-            self.user_stores
-                .insert((caller, self.store_counter), &new_store);
-            let mut store_ids = self.user_store_ids.get(caller).unwrap_or_default();
-            store_ids.push(self.store_counter);
-            self.user_store_ids.insert(caller, &store_ids);
+            // Remove the request
+            let request_id = 1;
+            let result = contract.delete_request(request_id);
+            assert!(result.is_ok());
 
-            self.env().emit_event(StoreCreated {
-                seller_address: caller,
-                store_id: self.store_counter,
-                store_name: name,
-                latitude,
-                longitude,
-            });
-            Ok(())
+            // Check if the request was removed
+            let request = contract.get_request(request_id);
+            assert_eq!(request, None);
+        }
+
+        #[test]
+        pub fn toggle_location() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+
+            // Create a buyer and a request
+            let buyer_name = "Bob".to_string();
+            let buyer_phone = "0987654321".to_string();
+            let latitude = 98765;
+            let longitude = 56789;
+            let buyer_account_type = AccountType::Buyer;
+            contract
+                .create_user(
+                    buyer_name.clone(),
+                    masked_phone(&buyer_phone),
+                    latitude,
+                    longitude,
+                    buyer_account_type,
+                )
+                .unwrap();
+
+            let enable_location = contract.get_location_preference();
+            assert!(enable_location);
+
+            // Toggle location
+            contract.toggle_location(true).unwrap();
+            let enable_location = contract.get_location_preference();
+            assert!(enable_location);
+
+            contract.toggle_location(false).unwrap();
+            let enable_location = contract.get_location_preference();
+            assert!(!enable_location);
         }
 
-        #[ink(message)]
-        pub fn create_request(
-            &mut self,
-            name: String,
-            description: String,
-            images: Vec<String>,
-            latitude: i128,
-            longitude: i128,
-        ) -> Result<()> {
-            let caller = self.env().caller();
-            let user = self
-                .users
-                .get(caller)
-                .ok_or(MarketplaceError::InvalidUser)?;
+        #[test]
+        fn test_accept_offer_requires_exact_deposit() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            // Create a buyer and a request
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            contract
+                .create_request(
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
+                )
+                .unwrap();
 
-            if user.account_type != AccountType::Buyer {
-                return Err(MarketplaceError::OnlyBuyersAllowed);
-            }
+            // Create a seller, a store and an offer
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
+                )
+                .unwrap();
+            set_buyer_env();
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
+            fund_buyer();
 
-            self.request_counter = self.request_counter.checked_add(1).unwrap();
-            let new_request = Request {
-                id: self.request_counter,
-                name: name.clone(),
-                buyer_id: user.id,
-                sellers_price_quote: 0,
-                seller_ids: Vec::new(),
-                offer_ids: Vec::new(),
-                locked_seller_id: 0,
-                description: description.clone(),
-                images: images.clone(),
-                created_at: self.env().block_timestamp(),
-                lifecycle: RequestLifecycle::Pending,
-                location: Location {
-                    latitude,
-                    longitude,
-                },
-                updated_at: self.env().block_timestamp(),
-            };
+            let result = ink::env::pay_with_call!(contract.accept_offer(offer_id, 100), 99);
+            assert_eq!(result, Err(MarketplaceError::InsufficientDeposit));
 
-            self.requests.insert(self.request_counter, &new_request);
-            self.env().emit_event(RequestCreated {
-                request_id: self.request_counter,
-                buyer_address: caller,
-                request_name: name,
-                latitude,
-                longitude,
-                images,
-                lifecycle: 0,
-                description,
-                buyer_id: user.id,
-                seller_ids: Vec::new(),
-                sellers_price_quote: 0,
-                locked_seller_id: 0,
-                created_at: self.env().block_timestamp(),
-                updated_at: self.env().block_timestamp(),
-            });
-            Ok(())
+            let result = ink::env::pay_with_call!(contract.accept_offer(offer_id, 100), 101);
+            assert_eq!(result, Err(MarketplaceError::IncorrectDeposit));
+
+            let result = ink::env::pay_with_call!(contract.accept_offer(offer_id, 100), 100);
+            assert!(result.is_ok());
+            assert_eq!(contract.get_escrowed_amount(request_id), 100);
         }
 
-        #[ink(message)]
-        pub fn delete_request(&mut self, request_id: u64) -> Result<()> {
-            let caller = self.env().caller();
+        #[test]
+        fn test_mark_request_as_completed_releases_escrow() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            // Create a buyer and a request
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            contract
+                .create_request(
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
+                )
+                .unwrap();
 
-            // Fetch the request, or return an error if it doesn't exist
-            let request = self
-                .requests
-                .get(request_id)
-                .ok_or(MarketplaceError::InvalidRequest)?;
+            // Create a seller, a store and an offer
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
+                )
+                .unwrap();
+            set_buyer_env();
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            fund_buyer();
+            ink::env::pay_with_call!(contract.accept_offer(offer_id, 100), 100).unwrap();
+
+            // Completion is only possible once the lock window has elapsed
+            let result = contract.mark_request_as_completed(request_id);
+            assert_eq!(
+                result,
+                Err(MarketplaceError::InvalidLifecycleTransition {
+                    from: RequestLifecycle::AcceptedByBuyer,
+                    to: RequestLifecycle::Completed,
+                })
+            );
 
-            // Check if the caller is the buyer who created the request
-            let user = self
-                .users
-                .get(caller)
-                .ok_or(MarketplaceError::InvalidUser)?;
+            let seller_balance = balance_of(accounts.bob);
+            ink::env::test::set_block_timestamp::<DefaultEnvironment>(
+                contract.config.time_to_lock + 1,
+            );
+            contract.mark_request_as_completed(request_id).unwrap();
 
-            if request.buyer_id != user.id {
-                return Err(MarketplaceError::UnauthorizedBuyer);
-            }
+            let request = contract.get_request(request_id).unwrap();
+            assert_eq!(request.lifecycle, RequestLifecycle::Completed);
+            assert_eq!(request.escrowed_amount, 0);
+            assert_eq!(balance_of(accounts.bob), seller_balance + 100);
+        }
 
-            if request.lifecycle != RequestLifecycle::Pending {
-                return Err(MarketplaceError::RequestLocked);
-            }
+        #[test]
+        fn test_accepted_request_rejects_offers_and_locks_after_window() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            // Create a buyer and a request
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            contract
+                .create_request(
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
+                )
+                .unwrap();
+
+            // Create a seller, a store and an offer
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
+                )
+                .unwrap();
+            set_buyer_env();
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
+            fund_buyer();
+            ink::env::pay_with_call!(contract.accept_offer(offer_id, 100), 100).unwrap();
+
+            // No new offers once the buyer has accepted one
+            ink::env::test::set_caller::<DefaultEnvironment>(
+                ink::env::test::default_accounts::<DefaultEnvironment>().django,
+            );
+            contract
+                .create_user(
+                    "Django".to_string(),
+                    masked_phone("5555555555"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "Late Store".to_string(),
+                    String::new(),
+                    masked_phone("5555555555"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            let result = contract.create_offer(request_id, 90, Vec::new(), contract.store_counter);
+            assert_eq!(
+                result,
+                Err(MarketplaceError::InvalidLifecycleTransition {
+                    from: RequestLifecycle::AcceptedByBuyer,
+                    to: RequestLifecycle::AcceptedBySeller,
+                })
+            );
 
-            // Remove the request from storage
-            self.requests.take(request_id);
+            assert_eq!(
+                contract.lock_request(request_id),
+                Err(MarketplaceError::RequestNotLocked)
+            );
+            // The deadline is fixed at acceptance, so widening the window later
+            // does not delay the lock
+            set_buyer_env();
+            contract
+                .set_config(MarketplaceConfig {
+                    time_to_lock: MAX_TIME_TO_LOCK,
+                    ..MarketplaceConfig::default()
+                })
+                .unwrap();
+            let lock_at = contract.get_request(request_id).unwrap().lock_at;
+            assert_eq!(lock_at, DEFAULT_TIME_TO_LOCK);
+            ink::env::test::set_block_timestamp::<DefaultEnvironment>(lock_at + 1);
+            contract.lock_request(request_id).unwrap();
+            assert_eq!(
+                contract.get_request(request_id).unwrap().lifecycle,
+                RequestLifecycle::RequestLocked
+            );
 
-            // Emit an event for the removed request
-            self.env().emit_event(RequestRemoved {
-                request_id,
-                buyer_address: caller,
-                removed_at: self.env().block_timestamp(),
-            });
+            // A locked request can no longer be withdrawn
+            set_buyer_env();
+            assert_eq!(
+                contract.delete_request(request_id),
+                Err(MarketplaceError::InvalidLifecycleTransition {
+                    from: RequestLifecycle::RequestLocked,
+                    to: RequestLifecycle::Cancelled,
+                })
+            );
 
-            Ok(())
+            let changes = ink::env::test::recorded_events()
+                .filter(|event| {
+                    <RequestLifecycleChanged as ink::env::Event>::SIGNATURE_TOPIC
+                        .map(|topic| event.topics[0] == topic)
+                        .unwrap_or(false)
+                })
+                .count();
+            // Pending -> AcceptedBySeller -> AcceptedByBuyer -> RequestLocked
+            assert_eq!(changes, 3);
         }
 
-        #[ink(message)]
-        pub fn create_offer(
-            &mut self,
-            request_id: u64,
-            price: i64,
-            images: Vec<String>,
-            store_name: String,
-        ) -> Result<()> {
-            let caller = self.env().caller();
+        #[test]
+        fn test_cancel_accepted_request_pays_cancellation_fee() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            assert_eq!(
+                contract.set_cancellation_fee_bps(10_001),
+                Err(MarketplaceError::InvalidFee)
+            );
+            contract.set_cancellation_fee_bps(1_000).unwrap();
 
-            // Fetch user and validate seller status
-            let user = self
-                .users
-                .get(caller)
-                .ok_or(MarketplaceError::InvalidUser)?;
+            // Create a buyer and a request
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            contract
+                .create_request(
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
+                )
+                .unwrap();
 
-            if user.account_type != AccountType::Seller {
-                return Err(MarketplaceError::OnlySellersAllowed);
-            }
+            // Create a seller, a store and an offer
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
+                )
+                .unwrap();
+            set_buyer_env();
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
+            fund_buyer();
+            let buyer_balance = balance_of(accounts.alice);
+            let seller_balance = balance_of(accounts.bob);
+            ink::env::pay_with_call!(contract.accept_offer(offer_id, 100), 100).unwrap();
+            contract.cancel_request(request_id).unwrap();
 
-            // Fetch the request and validate its existence
-            let mut request = self
-                .requests
-                .get(request_id)
-                .ok_or(MarketplaceError::InvalidRequest)?;
+            let request = contract.get_request(request_id).unwrap();
+            assert_eq!(request.lifecycle, RequestLifecycle::Cancelled);
+            assert_eq!(request.escrowed_amount, 0);
+            assert_eq!(balance_of(accounts.alice), buyer_balance - 10);
+            assert_eq!(balance_of(accounts.bob), seller_balance + 10);
+
+            let offer = contract.get_offer(offer_id).unwrap();
+            assert!(offer.is_void);
+            assert!(!offer.is_accepted);
+
+            assert_eq!(
+                contract.cancel_request(request_id),
+                Err(MarketplaceError::InvalidLifecycleTransition {
+                    from: RequestLifecycle::Cancelled,
+                    to: RequestLifecycle::Cancelled,
+                })
+            );
+        }
 
-            // Check if the request is locked due to timeout or lifecycle status
-            if self.env().block_timestamp()
-                > request.updated_at.checked_add(self.TIME_TO_LOCK).unwrap()
-                && request.lifecycle == RequestLifecycle::AcceptedByBuyer
-            {
-                return Err(MarketplaceError::RequestLocked);
-            }
+        #[test]
+        fn test_expired_request_rejects_offers_and_can_be_closed() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            // Create a buyer and a request
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            contract
+                .create_request(
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
+                )
+                .unwrap();
+
+            // Create a seller, a store and an offer
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
+                )
+                .unwrap();
+            set_buyer_env();
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
+
+            ink::env::test::set_block_timestamp::<DefaultEnvironment>(1_000);
+            assert_eq!(
+                contract.create_request(
+                    "Too late".to_string(),
+                    String::new(),
+                    Vec::new(),
+                    98765,
+                    56789,
+                    RequestParams {
+                        expires_at: Some(1_000),
+                        ..Default::default()
+                    },
+                ),
+                Err(MarketplaceError::InvalidExpiry)
+            );
+            contract
+                .create_request(
+                    "Same-day delivery".to_string(),
+                    String::new(),
+                    Vec::new(),
+                    98765,
+                    56789,
+                    RequestParams {
+                        expires_at: Some(2_000),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            let expiring_id = contract.request_counter;
 
-            // Increment offer counter and create new offer
-            self.offer_counter = self.offer_counter.checked_add(1).unwrap();
+            set_seller_env();
+            contract
+                .create_offer(expiring_id, 100, Vec::new(), contract.store_counter)
+                .unwrap();
+            let expiring_offer_id = contract.offer_counter;
+            assert_eq!(
+                contract.close_expired_request(expiring_id),
+                Err(MarketplaceError::RequestNotExpired)
+            );
 
-            let new_offer = Offer {
-                id: self.offer_counter,
-                price,
-                images: images.clone(),
-                request_id,
-                store_name: store_name.clone(),
-                seller_id: user.id,
-                is_accepted: false,
-                created_at: self.env().block_timestamp(),
-                updated_at: self.env().block_timestamp(),
-                authority: caller.clone(),
-            };
+            ink::env::test::set_block_timestamp::<DefaultEnvironment>(2_000);
+            assert_eq!(
+                contract.create_offer(expiring_id, 90, Vec::new(), contract.store_counter),
+                Err(MarketplaceError::RequestExpired)
+            );
+            assert_eq!(contract.get_expired_requests().len(), 1);
+            assert_eq!(contract.get_active_requests()[0].id, request_id);
 
-            // Insert the new offer into storage
-            self.offers.insert(self.offer_counter, &new_offer);
+            // Anyone may close the expired request
+            ink::env::test::set_caller::<DefaultEnvironment>(
+                ink::env::test::default_accounts::<DefaultEnvironment>().django,
+            );
+            contract.close_expired_request(expiring_id).unwrap();
+            assert_eq!(contract.get_request(expiring_id), None);
+            assert_eq!(contract.get_offer(expiring_offer_id), None);
+            assert!(contract.get_offer(offer_id).is_some());
+            assert!(contract.get_expired_requests().is_empty());
+        }
 
-            if request.lifecycle == RequestLifecycle::Pending {
-                request.lifecycle = RequestLifecycle::AcceptedBySeller;
-            }
+        #[test]
+        fn test_deleted_request_removes_its_offers() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            // Create a buyer and a request
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            contract
+                .create_request(
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
+                )
+                .unwrap();
 
-            // Update the request with the new seller and offer details
-            request.seller_ids.push(user.id);
-            request.offer_ids.push(self.offer_counter);
-            self.requests.insert(request_id, &request);
+            // Create a seller, a store and an offer
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
+                )
+                .unwrap();
+            set_buyer_env();
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
 
-            // Emit event for offer creation
-            self.env().emit_event(OfferCreated {
-                offer_id: self.offer_counter,
-                seller_address: caller,
-                store_name: store_name.clone(),
-                price,
-                request_id,
-                images,
-                seller_id: user.id,
-                seller_ids: request.seller_ids.clone(),
-            });
+            set_buyer_env();
+            contract.delete_request(request_id).unwrap();
 
-            Ok(())
+            assert_eq!(contract.get_offer(offer_id), None);
+            assert_eq!(contract.get_seller_offer_for_request(request_id, 2), None);
+            assert!(contract.get_seller_offers(accounts.bob).is_empty());
         }
 
-        #[ink(message)]
-        pub fn accept_offer(&mut self, offer_id: u64) -> Result<()> {
-            let caller = self.env().caller();
+        #[test]
+        fn test_update_and_withdraw_offer() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            // Create a buyer and a request
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            contract
+                .create_request(
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
+                )
+                .unwrap();
 
-            // Fetch the offer and validate its existence
-            let mut offer = self
-                .offers
-                .get(offer_id)
-                .ok_or(MarketplaceError::InvalidOffer)?;
+            // Create a seller, a store and an offer
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
+                )
+                .unwrap();
+            set_buyer_env();
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
 
-            let request_id = offer.request_id;
+            // Only the seller who made the offer may change it
+            assert_eq!(
+                contract.update_offer(offer_id, 80, Vec::new()),
+                Err(MarketplaceError::UnauthorizedSeller)
+            );
 
-            // Fetch the request and validate its existence
-            let mut request = self
-                .requests
-                .get(request_id)
-                .ok_or(MarketplaceError::InvalidRequest)?;
+            fund_buyer();
+            let buyer_balance = balance_of(accounts.alice);
+            ink::env::pay_with_call!(contract.accept_offer(offer_id, 100), 100).unwrap();
+            let cell = contract.get_request(request_id).unwrap().location.cell();
+            assert!(!contract.cell_request_positions.contains((cell, request_id)));
 
-            // Ensure the caller is the authorized buyer for this request
-            let buyer = self
-                .users
-                .get(caller)
-                .ok_or(MarketplaceError::InvalidUser)?;
+            // Repricing an accepted offer inside the lock window refunds the buyer
+            set_seller_env();
+            contract
+                .update_offer(offer_id, 80, vec!["fixed".to_string()])
+                .unwrap();
+            let offer = contract.get_offer(offer_id).unwrap();
+            assert_eq!(offer.price, 80);
+            assert!(!offer.is_accepted);
+            let request = contract.get_request(request_id).unwrap();
+            assert_eq!(request.lifecycle, RequestLifecycle::AcceptedBySeller);
+            assert_eq!(request.locked_seller_id, 0);
+            assert_eq!(balance_of(accounts.alice), buyer_balance);
+            assert!(contract.cell_request_positions.contains((cell, request_id)));
 
-            if buyer.account_type != AccountType::Buyer {
-                return Err(MarketplaceError::OnlyBuyersAllowed);
-            }
+            // The buyer has to agree to the new price explicitly
+            set_buyer_env();
+            assert_eq!(
+                contract.accept_offer(offer_id, 100),
+                Err(MarketplaceError::OfferPriceChanged)
+            );
+            set_seller_env();
 
-            if request.buyer_id != buyer.id {
-                return Err(MarketplaceError::UnauthorizedBuyer);
-            }
+            contract.withdraw_offer(offer_id).unwrap();
+            assert_eq!(contract.get_offer(offer_id), None);
+            let request = contract.get_request(request_id).unwrap();
+            assert!(request.offer_ids.is_empty());
+            assert!(request.seller_ids.is_empty());
+            assert_eq!(request.lifecycle, RequestLifecycle::Pending);
+        }
 
-            // Check if the offer has already been accepted
-            if offer.is_accepted {
-                return Err(MarketplaceError::OfferAlreadyAccepted);
-            }
+        #[test]
+        fn test_counter_offer_negotiation() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            // Create a buyer and a request
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            contract
+                .create_request(
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
+                )
+                .unwrap();
 
-            // Check if the request is locked due to timeout or lifecycle status
-            if self.env().block_timestamp()
-                > request.updated_at.checked_add(self.TIME_TO_LOCK).unwrap()
-                && request.lifecycle == RequestLifecycle::AcceptedByBuyer
-            {
-                return Err(MarketplaceError::RequestLocked);
-            }
+            // Create a seller, a store and an offer
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
+                )
+                .unwrap();
+            set_buyer_env();
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
 
-            // Update previous offers for the same request to set `is_accepted` to false
-            for offer_id in request.offer_ids.iter() {
-                if let Some(mut previous_offer) = self.offers.get(*offer_id) {
-                    if previous_offer.is_accepted && previous_offer.request_id == request_id {
-                        previous_offer.is_accepted = false;
-                        self.offers.insert(*offer_id, &previous_offer);
+            contract.counter_offer(offer_id, 70).unwrap();
+            assert_eq!(
+                contract.counter_offer(offer_id, 60),
+                Err(MarketplaceError::CounterOfferPending)
+            );
 
-                        // Emit event for un-accepting the previous offer
-                        self.env().emit_event(OfferAccepted {
-                            offer_id: previous_offer.id,
-                            buyer_address: caller,
-                            is_accepted: false,
-                        });
-                    }
-                }
-            }
+            // The seller meets the buyer halfway, then the buyer counters again
+            set_seller_env();
+            contract
+                .respond_to_counter_offer(offer_id, CounterOfferResponse::Counter(85))
+                .unwrap();
+            set_buyer_env();
+            contract.counter_offer(offer_id, 80).unwrap();
+            set_seller_env();
+            contract
+                .respond_to_counter_offer(offer_id, CounterOfferResponse::Accept)
+                .unwrap();
+            assert_eq!(
+                contract.respond_to_counter_offer(offer_id, CounterOfferResponse::Accept),
+                Err(MarketplaceError::NoCounterOffer)
+            );
 
-            // Accept the current offer
-            offer.is_accepted = true;
-            self.offers.insert(offer_id, &offer);
-            request.locked_seller_id = offer.seller_id;
-            request.sellers_price_quote = offer.price;
-            request.lifecycle = RequestLifecycle::AcceptedByBuyer;
-            request.updated_at = self.env().block_timestamp();
-            self.requests.insert(request_id, &request);
+            let offer = contract.get_offer(offer_id).unwrap();
+            assert_eq!(offer.price, 80);
+            assert_eq!(offer.negotiation_rounds, 3);
 
-            // Emit events for request and offer acceptance
-            self.env().emit_event(RequestAccepted {
-                request_id,
-                offer_id,
-                seller_id: offer.seller_id,
-                updated_at: request.updated_at,
-                sellers_price_quote: offer.price,
-            });
+            // The agreed price flows into the regular acceptance, after which a
+            // pending counter can no longer reprice the offer
+            set_buyer_env();
+            contract.counter_offer(offer_id, 75).unwrap();
+            fund_buyer();
+            ink::env::pay_with_call!(contract.accept_offer(offer_id, 80), 80).unwrap();
+            let request = contract.get_request(request_id).unwrap();
+            assert_eq!(request.sellers_price_quote, 80);
+            assert_eq!(request.locked_seller_id, offer.seller_id);
+            set_seller_env();
+            assert_eq!(
+                contract.respond_to_counter_offer(offer_id, CounterOfferResponse::Accept),
+                Err(MarketplaceError::OfferAlreadyAccepted)
+            );
 
-            self.env().emit_event(OfferAccepted {
-                offer_id,
-                buyer_address: caller,
-                is_accepted: true,
-            });
+            // Voiding the offer drops the pending counter
+            set_buyer_env();
+            contract.cancel_request(request_id).unwrap();
+            assert_eq!(contract.get_offer(offer_id).unwrap().counter_price, None);
+            set_seller_env();
+            assert_eq!(
+                contract.respond_to_counter_offer(offer_id, CounterOfferResponse::Accept),
+                Err(MarketplaceError::InvalidOffer)
+            );
+        }
 
-            Ok(())
+        #[test]
+        fn test_counter_offer_rounds_are_bounded() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            // Create a buyer and a request
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            contract
+                .create_request(
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
+                )
+                .unwrap();
+
+            // Create a seller, a store and an offer
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
+                )
+                .unwrap();
+            set_buyer_env();
+            let offer_id = contract.offer_counter;
+
+            for round in 0..MAX_NEGOTIATION_ROUNDS {
+                set_buyer_env();
+                contract
+                    .counter_offer(offer_id, 50 + round as Balance)
+                    .unwrap();
+                set_seller_env();
+                contract
+                    .respond_to_counter_offer(offer_id, CounterOfferResponse::Reject)
+                    .unwrap();
+            }
+
+            set_buyer_env();
+            assert_eq!(
+                contract.counter_offer(offer_id, 60),
+                Err(MarketplaceError::NegotiationLimitReached)
+            );
         }
 
-        #[ink(message)]
-        pub fn mark_request_as_completed(&mut self, request_id: u64) -> Result<()> {
-            let caller = self.env().caller();
+        #[test]
+        fn test_sealed_bid_commit_and_reveal() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            // Create a buyer
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
 
-            let mut request = self
-                .requests
-                .get(request_id)
-                .ok_or(MarketplaceError::InvalidRequest)?;
+            // Create a seller and a store
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            set_buyer_env();
 
-            let buyer = self
-                .users
-                .get(caller)
-                .ok_or(MarketplaceError::InvalidUser)?;
+            contract
+                .create_request(
+                    "Sealed".to_string(),
+                    String::new(),
+                    Vec::new(),
+                    98765,
+                    56789,
+                    RequestParams {
+                        bidding_mode: BiddingMode::Sealed {
+                            commit_ends_at: 1_000,
+                            reveal_ends_at: 2_000,
+                        },
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            let request_id = contract.request_counter;
+
+            let salt = [7u8; 32];
+            let commitment = |seller: AccountId, price: Balance| {
+                let mut output = [0u8; 32];
+                ink::env::hash_encoded::<Blake2x256, _>(
+                    &(request_id, seller, price, salt),
+                    &mut output,
+                );
+                Hash::from(output)
+            };
 
-            if buyer.account_type != AccountType::Buyer {
-                return Err(MarketplaceError::OnlyBuyersAllowed);
-            }
+            // Bob commits and reveals; Django copies Bob's commitment but cannot
+            // reveal it as his own
+            set_seller_env();
+            assert_eq!(
+                contract.create_offer(request_id, 90, Vec::new(), contract.store_counter),
+                Err(MarketplaceError::WrongBiddingMode)
+            );
+            contract
+                .commit_offer(request_id, commitment(accounts.bob, 90))
+                .unwrap();
+            ink::env::test::set_caller::<DefaultEnvironment>(accounts.django);
+            contract
+                .create_user(
+                    "Django".to_string(),
+                    masked_phone("5555555555"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .commit_offer(request_id, commitment(accounts.bob, 90))
+                .unwrap();
 
-            if request.buyer_id != buyer.id {
-                return Err(MarketplaceError::UnauthorizedBuyer);
-            }
+            set_seller_env();
+            assert_eq!(
+                contract.reveal_offer(request_id, 90, salt, Vec::new(), contract.store_counter),
+                Err(MarketplaceError::BiddingInProgress)
+            );
 
-            if request.lifecycle != RequestLifecycle::AcceptedByBuyer {
-                return Err(MarketplaceError::RequestNotAccepted);
-            }
+            ink::env::test::set_block_timestamp::<DefaultEnvironment>(1_000);
+            assert_eq!(
+                contract.commit_offer(request_id, commitment(accounts.bob, 85)),
+                Err(MarketplaceError::BiddingWindowClosed)
+            );
+            ink::env::test::set_caller::<DefaultEnvironment>(accounts.django);
+            assert_eq!(
+                contract.reveal_offer(request_id, 90, salt, Vec::new(), contract.store_counter),
+                Err(MarketplaceError::InvalidReveal)
+            );
+            set_seller_env();
+            assert_eq!(
+                contract.reveal_offer(request_id, 85, salt, Vec::new(), contract.store_counter),
+                Err(MarketplaceError::InvalidReveal)
+            );
+            contract
+                .reveal_offer(request_id, 90, salt, Vec::new(), contract.store_counter)
+                .unwrap();
+            let offer_id = contract.offer_counter;
+            assert_eq!(contract.get_offer(offer_id).unwrap().price, 90);
 
-            if request.updated_at.checked_add(self.TIME_TO_LOCK).unwrap()
-                > self.env().block_timestamp()
-            {
-                return Err(MarketplaceError::RequestNotLocked);
-            }
+            set_buyer_env();
+            assert_eq!(
+                contract.accept_offer(offer_id, 90),
+                Err(MarketplaceError::BiddingInProgress)
+            );
 
-            request.lifecycle = RequestLifecycle::Completed;
-            request.updated_at = self.env().block_timestamp();
-            self.requests.insert(request_id, &request);
+            ink::env::test::set_block_timestamp::<DefaultEnvironment>(2_000);
+            contract.discard_unrevealed_commitments(request_id).unwrap();
+            let django_id = contract.get_user(accounts.django).unwrap().id;
+            assert!(!contract.offer_commitments.contains((request_id, django_id)));
+            assert_eq!(contract.get_offer_by_request(request_id).len(), 1);
 
-            Ok(())
+            fund_buyer();
+            ink::env::pay_with_call!(contract.accept_offer(offer_id, 90), 90).unwrap();
         }
 
-        #[ink(message)]
-        pub fn toggle_location(&mut self, enabled: bool) -> Result<()> {
-            let caller = self.env().caller();
-            let mut user = self
-                .users
-                .get(caller)
-                .ok_or(MarketplaceError::InvalidUser)?;
-
-            user.location_enabled = enabled;
+        #[test]
+        fn test_commit_offer_requires_open_request() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            // Create a buyer
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
 
-            self.users.insert(caller, &user);
-            self.env().emit_event(LocationEnabled {
-                authority: caller,
-                location_enabled: enabled,
-                user_id: user.id,
-                created_at: self.env().block_timestamp(),
-                updated_at: self.env().block_timestamp(),
-            });
-            Ok(())
-        }
+            // Create a seller and a store
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            set_buyer_env();
 
-        #[ink(message)]
-        pub fn get_location_preference(&self) -> bool {
-            let caller = self.env().caller();
+            let sealed = |expires_at| RequestParams {
+                expires_at,
+                bidding_mode: BiddingMode::Sealed {
+                    commit_ends_at: 1_000,
+                    reveal_ends_at: 2_000,
+                },
+                ..Default::default()
+            };
+            contract
+                .create_request(
+                    "Expiring".to_string(),
+                    String::new(),
+                    Vec::new(),
+                    98765,
+                    56789,
+                    sealed(Some(500)),
+                )
+                .unwrap();
+            let expiring_id = contract.request_counter;
+            contract
+                .create_request(
+                    "Cancelled".to_string(),
+                    String::new(),
+                    Vec::new(),
+                    98765,
+                    56789,
+                    sealed(None),
+                )
+                .unwrap();
+            let cancelled_id = contract.request_counter;
+            contract.cancel_request(cancelled_id).unwrap();
 
-            let user = self.users.get(caller).unwrap();
-            return user.location_enabled;
-        }
+            set_seller_env();
+            let commitment = Hash::from([1u8; 32]);
+            contract.commit_offer(expiring_id, commitment).unwrap();
+            ink::env::test::set_block_timestamp::<DefaultEnvironment>(600);
+            assert_eq!(
+                contract.commit_offer(expiring_id, commitment),
+                Err(MarketplaceError::RequestExpired)
+            );
+            assert_eq!(
+                contract.commit_offer(cancelled_id, commitment),
+                Err(MarketplaceError::InvalidLifecycleTransition {
+                    from: RequestLifecycle::Cancelled,
+                    to: RequestLifecycle::AcceptedBySeller,
+                })
+            );
 
-        #[ink(message)]
-        pub fn get_user(&self, user_address: AccountId) -> Option<User> {
-            self.users.get(user_address)
+            // Closing the expired request drops its commitments with it
+            contract.close_expired_request(expiring_id).unwrap();
+            assert!(!contract.request_commitments.contains(expiring_id));
+            let seller_id = contract.get_user(accounts.bob).unwrap().id;
+            assert!(!contract
+                .offer_commitments
+                .contains((expiring_id, seller_id)));
         }
 
-        #[ink(message)]
-        pub fn get_request(&self, request_id: u64) -> Option<Request> {
-            self.requests.get(request_id)
-        }
+        #[test]
+        fn test_reverse_auction_settles_lowest_offer() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            // Create a buyer
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
 
-        #[ink(message)]
-        pub fn get_offer(&self, offer_id: u64) -> Option<Offer> {
-            self.offers.get(offer_id)
-        }
+            // Create a seller and a store
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            set_buyer_env();
+            let store_id = contract.store_counter;
+            fund_buyer();
 
-        #[ink(message)]
-        pub fn get_offer_by_request(&self, request_id: u64) -> Vec<Offer> {
-            let mut request_offers = Vec::new();
-            let request = self.requests.get(request_id).unwrap();
-            for offer_id in request.offer_ids.iter() {
-                if let Some(offer) = self.offers.get(*offer_id) {
-                    request_offers.push(offer.clone());
-                }
-            }
-            request_offers
-        }
+            let auction = BiddingMode::ReverseAuction {
+                closes_at: 1_000,
+                reserve_price: 100,
+            };
+            let create_auction = |contract: &mut Marketplace, reserve: Balance| {
+                ink::env::test::transfer_in::<DefaultEnvironment>(reserve);
+                contract.create_request(
+                    "Auction".to_string(),
+                    String::new(),
+                    Vec::new(),
+                    98765,
+                    56789,
+                    RequestParams {
+                        bidding_mode: BiddingMode::ReverseAuction {
+                            closes_at: 1_000,
+                            reserve_price: reserve,
+                        },
+                        ..Default::default()
+                    },
+                )
+            };
 
-        #[ink(message)]
-        pub fn get_user_requests(&self, user_address: AccountId) -> Vec<Request> {
-            let mut user_requests = Vec::new();
-            let user = self.users.get(user_address).unwrap();
-            for request_id in 0..=self.request_counter {
-                if let Some(request) = self.requests.get(request_id) {
-                    if request.buyer_id == user.id {
-                        user_requests.push(request.clone());
-                    }
-                }
-            }
-            user_requests
-        }
+            // The reserve has to be escrowed up front
+            assert_eq!(
+                contract.create_request(
+                    "Auction".to_string(),
+                    String::new(),
+                    Vec::new(),
+                    98765,
+                    56789,
+                    RequestParams {
+                        bidding_mode: auction,
+                        ..Default::default()
+                    },
+                ),
+                Err(MarketplaceError::InsufficientDeposit)
+            );
+            create_auction(&mut contract, 100).unwrap();
+            let request_id = contract.request_counter;
+            assert_eq!(contract.get_escrowed_amount(request_id), 100);
 
-        #[ink(message)]
-        pub fn get_all_requests(&self) -> Vec<Request> {
-            let mut all_requests = Vec::new();
-            for request_id in 0..=self.request_counter {
-                if let Some(request) = self.requests.get(request_id) {
-                    all_requests.push(request.clone());
-                }
+            // Bob bids 90; Django and Eve tie at 80, Django first
+            set_seller_env();
+            contract
+                .create_offer(request_id, 90, Vec::new(), store_id)
+                .unwrap();
+            for (seller, at) in [(accounts.django, 10), (accounts.eve, 20)] {
+                ink::env::test::set_block_timestamp::<DefaultEnvironment>(at);
+                ink::env::test::set_caller::<DefaultEnvironment>(seller);
+                contract
+                    .create_user(
+                        "Seller".to_string(),
+                        masked_phone("5555555555"),
+                        98765,
+                        56789,
+                        AccountType::Seller,
+                    )
+                    .unwrap();
+                contract
+                    .create_store(
+                        "Store".to_string(),
+                        String::new(),
+                        masked_phone("5555555555"),
+                        98765,
+                        56789,
+                    )
+                    .unwrap();
+                contract
+                    .create_offer(request_id, 80, Vec::new(), contract.store_counter)
+                    .unwrap();
             }
-            all_requests
-        }
+            let winning_offer_id = contract.offer_counter - 1;
 
-        #[ink(message)]
-        pub fn get_user_stores(&self, user_address: AccountId) -> Vec<Store> {
-            let mut user_stores = Vec::new();
-            let store_ids = self.user_store_ids.get(user_address).unwrap_or_default();
-            for store_id in store_ids.iter() {
-                if let Some(store) = self.user_stores.get((user_address, *store_id)) {
-                    user_stores.push(store.clone());
-                }
-            }
-            user_stores
-        }
+            set_buyer_env();
+            assert_eq!(
+                contract.accept_offer(winning_offer_id, 80),
+                Err(MarketplaceError::WrongBiddingMode)
+            );
+            assert_eq!(
+                contract.settle_auction(request_id),
+                Err(MarketplaceError::BiddingInProgress)
+            );
 
-        #[ink(message)]
-        pub fn get_user_by_id(&self, user_id: u64) -> Option<User> {
-            if let Some(account_id) = self.user_ids.get(&user_id) {
-                self.users.get(&account_id) // Retrieve the user by the AccountId
-            } else {
-                None
-            }
-        }
+            ink::env::test::set_block_timestamp::<DefaultEnvironment>(1_000);
+            set_seller_env();
+            assert_eq!(
+                contract.update_offer(winning_offer_id - 1, 70, Vec::new()),
+                Err(MarketplaceError::BiddingWindowClosed)
+            );
 
-        #[ink(message)]
-        pub fn get_seller_offers(&self, seller_address: AccountId) -> Vec<Offer> {
-            let mut seller_offers = Vec::new();
-            for offer_id in 0..=self.offer_counter {
-                if let Some(offer) = self.offers.get(offer_id) {
-                    if offer.authority == seller_address {
-                        seller_offers.push(offer.clone());
-                    }
-                }
-            }
-            seller_offers
-        }
-    }
+            // Once closed, settlement is the only way forward for the buyer too
+            set_buyer_env();
+            assert_eq!(
+                contract.cancel_request(request_id),
+                Err(MarketplaceError::BiddingWindowClosed)
+            );
+            assert_eq!(
+                contract.delete_request(request_id),
+                Err(MarketplaceError::BiddingWindowClosed)
+            );
 
-    #[cfg(test)]
-    mod tests {
-        use super::*;
-        use ink::env::DefaultEnvironment;
+            // Anyone can settle; the unused part of the reserve goes back to the buyer
+            let buyer_balance = balance_of(accounts.alice);
+            contract.settle_auction(request_id).unwrap();
+            let request = contract.get_request(request_id).unwrap();
+            assert_eq!(request.lifecycle, RequestLifecycle::AcceptedByBuyer);
+            assert_eq!(request.sellers_price_quote, 80);
+            assert_eq!(request.escrowed_amount, 80);
+            assert!(contract.get_offer(winning_offer_id).unwrap().is_accepted);
+            assert_eq!(balance_of(accounts.alice), buyer_balance + 20);
+            assert_eq!(
+                contract.settle_auction(request_id),
+                Err(MarketplaceError::OfferAlreadyAccepted)
+            );
 
-        fn set_buyer_env() {
-            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
-            ink::env::test::set_caller::<DefaultEnvironment>(accounts.alice);
-            ink::env::test::set_callee::<DefaultEnvironment>(accounts.charlie);
-        }
+            // The winner cannot back out, which would leave the auction unsettled
+            let winner = contract.get_offer(winning_offer_id).unwrap().authority;
+            ink::env::test::set_caller::<DefaultEnvironment>(winner);
+            assert_eq!(
+                contract.withdraw_offer(winning_offer_id),
+                Err(MarketplaceError::OfferAlreadyAccepted)
+            );
 
-        fn set_seller_env() {
-            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
-            ink::env::test::set_caller::<DefaultEnvironment>(accounts.bob);
-            ink::env::test::set_callee::<DefaultEnvironment>(accounts.charlie);
+            // Without an offer under the reserve the auction is cancelled
+            ink::env::test::set_block_timestamp::<DefaultEnvironment>(0);
+            set_buyer_env();
+            create_auction(&mut contract, 50).unwrap();
+            let request_id = contract.request_counter;
+            set_seller_env();
+            contract
+                .create_offer(request_id, 60, Vec::new(), store_id)
+                .unwrap();
+            ink::env::test::set_block_timestamp::<DefaultEnvironment>(1_000);
+            assert_eq!(
+                contract.create_offer(request_id, 40, Vec::new(), store_id),
+                Err(MarketplaceError::BiddingWindowClosed)
+            );
+            let buyer_balance = balance_of(accounts.alice);
+            contract.settle_auction(request_id).unwrap();
+            let request = contract.get_request(request_id).unwrap();
+            assert_eq!(request.lifecycle, RequestLifecycle::Cancelled);
+            assert_eq!(request.escrowed_amount, 0);
+            assert_eq!(balance_of(accounts.alice), buyer_balance + 50);
         }
 
         #[test]
-        fn test_contract_initialization() {
+        fn test_one_active_offer_per_seller() {
             set_buyer_env();
-            let contract = Marketplace::new();
-            assert_eq!(contract.user_counter, 0);
-            assert_eq!(contract.store_counter, 0);
-            assert_eq!(contract.request_counter, 0);
-            assert_eq!(contract.offer_counter, 0);
-            assert_eq!(contract.TIME_TO_LOCK, 900 * 1000);
-        }
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            // Create a buyer and a request
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            contract
+                .create_request(
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
+                )
+                .unwrap();
 
-        #[test]
-        fn test_create_user() {
+            // Create a seller, a store and an offer
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
+                )
+                .unwrap();
             set_buyer_env();
-            let mut contract = Marketplace::new();
-
-            let username = "Alice".to_string();
-            let phone = "1234567890".to_string();
-            let latitude = 12345;
-            let longitude = 54321;
-            let account_type = AccountType::Buyer;
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
 
-            let result = contract.create_user(
-                username.clone(),
-                phone.clone(),
-                latitude,
-                longitude,
-                account_type.clone(),
+            set_seller_env();
+            assert_eq!(
+                contract.create_offer(request_id, 90, Vec::new(), contract.store_counter),
+                Err(MarketplaceError::DuplicateOffer)
             );
-            assert!(result.is_ok());
 
-            let caller = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>().alice;
-            let user = contract.users.get(caller).unwrap();
+            // Withdrawing frees the slot for a new offer
+            contract.withdraw_offer(offer_id).unwrap();
+            contract
+                .create_offer(request_id, 90, Vec::new(), contract.store_counter)
+                .unwrap();
 
-            assert_eq!(user.username, username);
-            assert_eq!(user.phone, phone);
-            assert_eq!(user.location.latitude, latitude);
-            assert_eq!(user.location.longitude, longitude);
-            assert_eq!(user.account_type, account_type);
+            let request = contract.get_request(request_id).unwrap();
+            assert_eq!(request.seller_ids.len(), 1);
+            assert_eq!(request.offer_ids, vec![contract.offer_counter]);
+            let offer = contract
+                .get_seller_offer_for_request(request_id, request.seller_ids[0])
+                .unwrap();
+            assert_eq!(offer.price, 90);
         }
 
         #[test]
-        fn test_update_user() {
+        fn test_accepted_offer_cannot_change_after_lock() {
             set_buyer_env();
-            let mut contract = Marketplace::new();
-
-            let username = "Alice".to_string();
-            let phone = "1234567890".to_string();
-            let latitude = 12345;
-            let longitude = 54321;
-            let account_type = AccountType::Buyer;
-
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            // Create a buyer and a request
             contract
                 .create_user(
-                    username.clone(),
-                    phone.clone(),
-                    latitude,
-                    longitude,
-                    account_type.clone(),
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            contract
+                .create_request(
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
                 )
                 .unwrap();
 
-            // Update the user
-            let new_username = "AliceUpdated".to_string();
-            let new_phone = "0987654321".to_string();
-            let new_latitude = 67890;
-            let new_longitude = 98765;
-            let new_account_type = AccountType::Seller;
+            // Create a seller, a store and an offer
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
+                )
+                .unwrap();
+            set_buyer_env();
+            let offer_id = contract.offer_counter;
+            fund_buyer();
+            ink::env::pay_with_call!(contract.accept_offer(offer_id, 100), 100).unwrap();
 
-            let result = contract.update_user(
-                new_username.clone(),
-                new_phone.clone(),
-                new_latitude,
-                new_longitude,
-                new_account_type.clone(),
-            );
-            assert!(result.is_ok());
+            let time_to_lock = contract.config.time_to_lock;
+            ink::env::test::set_block_timestamp::<DefaultEnvironment>(time_to_lock + 1);
+            set_seller_env();
+            let locked = Err(MarketplaceError::InvalidLifecycleTransition {
+                from: RequestLifecycle::RequestLocked,
+                to: RequestLifecycle::AcceptedBySeller,
+            });
+            assert_eq!(contract.update_offer(offer_id, 80, Vec::new()), locked);
+            assert_eq!(contract.withdraw_offer(offer_id), locked);
+        }
 
-            let caller = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>().alice;
-            let user = contract.users.get(caller).unwrap();
+        #[test]
+        fn test_cancel_accepted_request_refunds_escrow() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            // Create a buyer and a request
+            contract
+                .create_user(
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            contract
+                .create_request(
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
+                )
+                .unwrap();
 
-            assert_eq!(user.username, new_username);
-            assert_eq!(user.phone, new_phone);
-            assert_eq!(user.location.latitude, new_latitude);
-            assert_eq!(user.location.longitude, new_longitude);
-            assert_eq!(user.account_type, new_account_type);
+            // Create a seller, a store and an offer
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
+                )
+                .unwrap();
+            set_buyer_env();
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            fund_buyer();
+            let buyer_balance = balance_of(accounts.alice);
+            ink::env::pay_with_call!(contract.accept_offer(offer_id, 100), 100).unwrap();
+            assert_eq!(balance_of(accounts.alice), buyer_balance - 100);
+
+            // Accepted requests are cancelled rather than deleted
+            assert_eq!(
+                contract.delete_request(request_id),
+                Err(MarketplaceError::OfferAlreadyAccepted)
+            );
+            contract.cancel_request(request_id).unwrap();
+            assert_eq!(balance_of(accounts.alice), buyer_balance);
         }
 
         #[test]
-        fn test_create_store() {
+        fn test_locked_request_cannot_be_cancelled() {
             set_buyer_env();
-            let mut contract = Marketplace::new();
-
-            // Create a seller
-            let username = "Alice".to_string();
-            let phone = "1234567890".to_string();
-            let latitude = 12345;
-            let longitude = 54321;
-            let account_type = AccountType::Seller;
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            // Create a buyer and a request
             contract
                 .create_user(
-                    username.clone(),
-                    phone.clone(),
-                    latitude,
-                    longitude,
-                    account_type,
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
+                )
+                .unwrap();
+            contract
+                .create_request(
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
                 )
                 .unwrap();
 
-            // Create a store
-            let store_name = "My Store".to_string();
-            let store_description = "Best Store".to_string();
-            let result = contract.create_store(
-                store_name.clone(),
-                store_description.clone(),
-                phone.clone(),
-                latitude,
-                longitude,
+            // Create a seller, a store and an offer
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
+                )
+                .unwrap();
+            set_buyer_env();
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
+            fund_buyer();
+            ink::env::pay_with_call!(contract.accept_offer(offer_id, 100), 100).unwrap();
+
+            let time_to_lock = contract.config.time_to_lock;
+            ink::env::test::set_block_timestamp::<DefaultEnvironment>(time_to_lock + 1);
+            assert_eq!(
+                contract.cancel_request(request_id),
+                Err(MarketplaceError::InvalidLifecycleTransition {
+                    from: RequestLifecycle::RequestLocked,
+                    to: RequestLifecycle::Cancelled,
+                })
             );
-            assert!(result.is_ok());
-
-            // Check store creation
-            let caller = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>().alice;
-            let stores = contract.get_user_stores(caller);
-            assert_eq!(stores.len(), 1);
-            assert_eq!(stores[0].name, store_name);
-            assert_eq!(stores[0].description, store_description);
+            assert_eq!(contract.get_escrowed_amount(request_id), 100);
         }
 
         #[test]
-        fn test_create_request() {
+        fn test_psp22_request_rejects_native_deposit() {
             set_buyer_env();
-            let mut contract = Marketplace::new();
-
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            let token = PaymentAsset::Psp22(accounts.django);
             // Create a buyer
-            let username = "Bob".to_string();
-            let phone = "0987654321".to_string();
-            let latitude = 98765;
-            let longitude = 56789;
-            let account_type = AccountType::Buyer;
             contract
                 .create_user(
-                    username.clone(),
-                    phone.clone(),
-                    latitude,
-                    longitude,
-                    account_type,
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
                 )
                 .unwrap();
 
-            // Create a request
-            let request_name = "Request 1".to_string();
-            let request_description = "Need this item".to_string();
-            let images = vec!["image1".to_string(), "image2".to_string()];
-            let result = contract.create_request(
-                request_name.clone(),
-                request_description.clone(),
-                images.clone(),
-                latitude,
-                longitude,
-            );
-            assert!(result.is_ok());
+            // Create a seller and a store
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            set_buyer_env();
 
-            // Check request creation
-            let requests = contract.get_all_requests();
-            assert_eq!(requests.len(), 1);
-            assert_eq!(requests[0].name, request_name);
-            assert_eq!(requests[0].description, request_description);
-            assert_eq!(requests[0].images, images);
+            contract
+                .create_request(
+                    "Request 2".to_string(),
+                    "Paid in stablecoins".to_string(),
+                    Vec::new(),
+                    98765,
+                    56789,
+                    RequestParams {
+                        payment_asset: token,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            let request_id = contract.request_counter;
+
+            set_seller_env();
+            contract
+                .create_offer(request_id, 100, Vec::new(), contract.store_counter)
+                .unwrap();
+            let offer = contract.get_offer(contract.offer_counter).unwrap();
+            assert_eq!(offer.payment_asset, token);
+
+            set_buyer_env();
+            fund_buyer();
+            let result = ink::env::pay_with_call!(contract.accept_offer(offer.id, 100), 100);
+            assert_eq!(result, Err(MarketplaceError::IncorrectDeposit));
         }
 
         #[test]
-        fn test_create_offer() {
+        fn test_dispute_refunds_buyer_on_majority_ruling() {
             set_buyer_env();
-            let mut contract = Marketplace::new();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            contract.add_arbitrator(accounts.django).unwrap();
+            contract.add_arbitrator(accounts.eve).unwrap();
+            contract.add_arbitrator(accounts.frank).unwrap();
+            contract.add_arbitrator(accounts.bob).unwrap();
 
             // Create a buyer and a request
-            let buyer_name = "Alice".to_string();
-            let buyer_phone = "0987654321".to_string();
-            let latitude = 98765;
-            let longitude = 56789;
-            let buyer_account_type = AccountType::Buyer;
             contract
                 .create_user(
-                    buyer_name.clone(),
-                    buyer_phone.clone(),
-                    latitude,
-                    longitude,
-                    buyer_account_type,
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
                 )
                 .unwrap();
-
-            let request_name = "Request 1".to_string();
-            let request_description = "Need this item".to_string();
-            let images = vec!["image1".to_string()];
             contract
                 .create_request(
-                    request_name.clone(),
-                    request_description.clone(),
-                    images.clone(),
-                    latitude,
-                    longitude,
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
                 )
                 .unwrap();
 
+            // Create a seller, a store and an offer
             set_seller_env();
-
-            // Create a seller and a store
-            let seller_name = "Bob".to_string();
-            let seller_phone = "1234567890".to_string();
-            let seller_account_type = AccountType::Seller;
             contract
                 .create_user(
-                    seller_name.clone(),
-                    seller_phone.clone(),
-                    latitude,
-                    longitude,
-                    seller_account_type,
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
                 )
                 .unwrap();
-
-            let store_name = "My Store".to_string();
-            let store_description = "Best Store".to_string();
             contract
                 .create_store(
-                    store_name.clone(),
-                    store_description,
-                    seller_phone.clone(),
-                    latitude,
-                    longitude,
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
                 )
                 .unwrap();
+            set_buyer_env();
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
+            fund_buyer();
+            let buyer_balance = balance_of(accounts.alice);
+            ink::env::pay_with_call!(contract.accept_offer(offer_id, 100), 100).unwrap();
+
+            // The seller disputes, then the buyer adds their side
+            set_seller_env();
+            contract
+                .open_dispute(request_id, "ipfs://seller-evidence".to_string())
+                .unwrap();
+            // A party cannot rule on their own dispute, nor count towards the panel
+            assert_eq!(
+                contract.vote_on_dispute(request_id, DisputeRuling::ReleaseToSeller),
+                Err(MarketplaceError::PartyToDispute)
+            );
+            assert_eq!(
+                contract.get_dispute(request_id).unwrap().panel,
+                vec![accounts.django, accounts.eve, accounts.frank]
+            );
+            set_buyer_env();
+            // Arbitrators appointed later do not sit on the panel
+            contract.add_arbitrator(accounts.charlie).unwrap();
+            contract
+                .add_dispute_evidence(request_id, "ipfs://buyer-evidence".to_string())
+                .unwrap();
+            assert_eq!(
+                contract.mark_request_as_completed(request_id),
+                Err(MarketplaceError::InvalidLifecycleTransition {
+                    from: RequestLifecycle::Disputed,
+                    to: RequestLifecycle::Completed,
+                })
+            );
+            assert_eq!(
+                contract.vote_on_dispute(request_id, DisputeRuling::RefundBuyer),
+                Err(MarketplaceError::NotArbitrator)
+            );
+
+            ink::env::test::set_caller::<DefaultEnvironment>(accounts.charlie);
+            assert_eq!(
+                contract.vote_on_dispute(request_id, DisputeRuling::RefundBuyer),
+                Err(MarketplaceError::NotArbitrator)
+            );
 
-            // Create an offer
-            let request_id = 1;
-            let offer_price = 100;
-            let offer_images = vec!["offer_image1".to_string()];
+            ink::env::test::set_caller::<DefaultEnvironment>(accounts.django);
+            contract
+                .vote_on_dispute(request_id, DisputeRuling::RefundBuyer)
+                .unwrap();
+            assert_eq!(
+                contract.vote_on_dispute(request_id, DisputeRuling::RefundBuyer),
+                Err(MarketplaceError::AlreadyVoted)
+            );
+            assert_eq!(
+                contract.get_request(request_id).unwrap().lifecycle,
+                RequestLifecycle::Disputed
+            );
 
+            ink::env::test::set_caller::<DefaultEnvironment>(accounts.eve);
             contract
-                .create_offer(
-                    request_id,
-                    offer_price,
-                    offer_images.clone(),
-                    store_name.clone(),
-                )
+                .vote_on_dispute(request_id, DisputeRuling::RefundBuyer)
                 .unwrap();
 
-            // Check offer creation
-            let offers = contract.get_offer_by_request(request_id);
-            assert_eq!(offers.len(), 1);
-            assert_eq!(offers[0].price, offer_price);
-            assert_eq!(offers[0].images, offer_images);
-            assert_eq!(offers[0].store_name, store_name);
+            let request = contract.get_request(request_id).unwrap();
+            assert_eq!(request.lifecycle, RequestLifecycle::Resolved);
+            assert_eq!(request.escrowed_amount, 0);
+            assert_eq!(balance_of(accounts.alice), buyer_balance);
+
+            let dispute = contract.get_dispute(request_id).unwrap();
+            assert_eq!(dispute.evidence.len(), 2);
+            assert_eq!(dispute.ruling, Some(DisputeRuling::RefundBuyer));
         }
 
         #[test]
-        fn test_accept_offer() {
+        fn test_dispute_panel_is_odd_and_settles_after_timeout() {
             set_buyer_env();
-            let mut contract = Marketplace::new();
-
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
             // Create a buyer and a request
-            let buyer_name = "Bob".to_string();
-            let buyer_phone = "0987654321".to_string();
-            let latitude = 98765;
-            let longitude = 56789;
-            let buyer_account_type = AccountType::Buyer;
             contract
                 .create_user(
-                    buyer_name.clone(),
-                    buyer_phone.clone(),
-                    latitude,
-                    longitude,
-                    buyer_account_type,
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
                 )
                 .unwrap();
-
-            let request_name = "Request 1".to_string();
-            let request_description = "Need this item".to_string();
-            let images = vec!["image1".to_string()];
             contract
                 .create_request(
-                    request_name.clone(),
-                    request_description.clone(),
-                    images.clone(),
-                    latitude,
-                    longitude,
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
                 )
                 .unwrap();
 
+            // Create a seller, a store and an offer
             set_seller_env();
-
-            // Create a seller and a store
-            let seller_name = "Alice".to_string();
-            let seller_phone = "1234567890".to_string();
-            let seller_account_type = AccountType::Seller;
             contract
                 .create_user(
-                    seller_name.clone(),
-                    seller_phone.clone(),
-                    latitude,
-                    longitude,
-                    seller_account_type,
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
                 )
                 .unwrap();
-
-            let store_name = "My Store".to_string();
-            let store_description = "Best Store".to_string();
             contract
                 .create_store(
-                    store_name.clone(),
-                    store_description,
-                    seller_phone.clone(),
-                    latitude,
-                    longitude,
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
                 )
                 .unwrap();
-
-            // Create an offer
-            let request_id = 1;
-            let offer_price = 100;
-            let offer_images = vec!["offer_image1".to_string()];
             contract
                 .create_offer(
-                    request_id,
-                    offer_price,
-                    offer_images.clone(),
-                    store_name.clone(),
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
                 )
                 .unwrap();
-
             set_buyer_env();
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
+            fund_buyer();
+            let buyer_balance = balance_of(accounts.alice);
+            ink::env::pay_with_call!(contract.accept_offer(offer_id, 100), 100).unwrap();
+
+            assert_eq!(
+                contract.open_dispute(request_id, "ipfs://evidence".to_string()),
+                Err(MarketplaceError::NoArbitrators)
+            );
 
-            // Accept the offer
-            let offer_id = 1;
-            let result = contract.accept_offer(offer_id);
-            assert!(result.is_ok());
+            // The latest of an even panel steps down
+            contract.add_arbitrator(accounts.django).unwrap();
+            contract.add_arbitrator(accounts.eve).unwrap();
+            contract
+                .open_dispute(request_id, "ipfs://evidence".to_string())
+                .unwrap();
+            assert_eq!(
+                contract.get_dispute(request_id).unwrap().panel,
+                vec![accounts.django]
+            );
+            ink::env::test::set_caller::<DefaultEnvironment>(accounts.eve);
+            assert_eq!(
+                contract.vote_on_dispute(request_id, DisputeRuling::ReleaseToSeller),
+                Err(MarketplaceError::NotArbitrator)
+            );
 
-            // // Check if the offer was accepted
-            let accepted_offer = contract.get_offer(offer_id).unwrap();
-            assert_eq!(accepted_offer.is_accepted, true);
+            // A revoked panel leaves the escrow to the timeout
+            set_buyer_env();
+            contract.remove_arbitrator(accounts.django).unwrap();
+            assert_eq!(
+                contract.settle_dispute(request_id),
+                Err(MarketplaceError::DisputeNotExpired)
+            );
+            ink::env::test::set_block_timestamp::<DefaultEnvironment>(DISPUTE_TIMEOUT);
+            ink::env::test::set_caller::<DefaultEnvironment>(accounts.frank);
+            contract.settle_dispute(request_id).unwrap();
 
-            // Check the request lifecycle
             let request = contract.get_request(request_id).unwrap();
-            assert_eq!(request.lifecycle, RequestLifecycle::AcceptedByBuyer);
-            assert_eq!(request.locked_seller_id, accepted_offer.seller_id);
+            assert_eq!(request.lifecycle, RequestLifecycle::Resolved);
+            assert_eq!(balance_of(accounts.alice), buyer_balance);
+            assert_eq!(
+                contract.get_dispute(request_id).unwrap().ruling,
+                Some(DisputeRuling::RefundBuyer)
+            );
         }
 
         #[test]
-        fn test_mark_request_as_completed() {
+        fn test_submit_review_updates_reputation() {
             set_buyer_env();
-            let mut contract = Marketplace::new();
-
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
             // Create a buyer and a request
-            let buyer_name = "Bob".to_string();
-            let buyer_phone = "0987654321".to_string();
-            let latitude = 98765;
-            let longitude = 56789;
-            let buyer_account_type = AccountType::Buyer;
             contract
                 .create_user(
-                    buyer_name.clone(),
-                    buyer_phone.clone(),
-                    latitude,
-                    longitude,
-                    buyer_account_type,
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
                 )
                 .unwrap();
-
-            let request_name = "Request 1".to_string();
-            let request_description = "Need this item".to_string();
-            let images = vec!["image1".to_string()];
             contract
                 .create_request(
-                    request_name.clone(),
-                    request_description.clone(),
-                    images.clone(),
-                    latitude,
-                    longitude,
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
                 )
                 .unwrap();
 
+            // Create a seller, a store and an offer
             set_seller_env();
-
-            // Create a seller and a store
-            let seller_name = "Alice".to_string();
-            let seller_phone = "1234567890".to_string();
-            let seller_account_type = AccountType::Seller;
             contract
                 .create_user(
-                    seller_name.clone(),
-                    seller_phone.clone(),
-                    latitude,
-                    longitude,
-                    seller_account_type,
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
                 )
                 .unwrap();
-
-            let store_name = "My Store".to_string();
-            let store_description = "Best Store".to_string();
             contract
                 .create_store(
-                    store_name.clone(),
-                    store_description,
-                    seller_phone.clone(),
-                    latitude,
-                    longitude,
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
                 )
                 .unwrap();
-
-            // Create an offer
-            let request_id = 1;
-            let offer_price = 100;
-            let offer_images = vec!["offer_image1".to_string()];
             contract
                 .create_offer(
-                    request_id,
-                    offer_price,
-                    offer_images.clone(),
-                    store_name.clone(),
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
                 )
                 .unwrap();
-
             set_buyer_env();
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
+            fund_buyer();
+            ink::env::pay_with_call!(contract.accept_offer(offer_id, 100), 100).unwrap();
+
+            // Reviews are only possible once the request is completed
+            assert_eq!(
+                contract.submit_review(request_id, 5, "ipfs://great".to_string()),
+                Err(MarketplaceError::RequestNotCompleted)
+            );
 
-            // // Accept the offer
-            let offer_id = 1;
-            contract.accept_offer(offer_id).unwrap();
+            let time_to_lock = contract.config.time_to_lock;
+            ink::env::test::set_block_timestamp::<DefaultEnvironment>(time_to_lock + 1);
+            contract.mark_request_as_completed(request_id).unwrap();
 
-            // // Mark the request as completed
-            // let result = contract.mark_request_as_completed(request_id).unwrap();
-            // assert!(result.is_ok());
+            assert_eq!(
+                contract.submit_review(request_id, 6, "ipfs://great".to_string()),
+                Err(MarketplaceError::InvalidRating)
+            );
+            contract
+                .submit_review(request_id, 4, "ipfs://great".to_string())
+                .unwrap();
+            assert_eq!(
+                contract.submit_review(request_id, 4, "ipfs://again".to_string()),
+                Err(MarketplaceError::AlreadyReviewed)
+            );
 
-            // // Check the request lifecycle
-            // let request = contract.get_request(request_id).unwrap();
-            // assert_eq!(request.lifecycle, RequestLifecycle::Completed);
+            set_seller_env();
+            contract
+                .submit_review(request_id, 5, "ipfs://prompt-payer".to_string())
+                .unwrap();
+
+            let seller_id = contract.get_offer(offer_id).unwrap().seller_id;
+            let reputation = contract.get_user_reputation(seller_id).unwrap();
+            assert_eq!(reputation.review_count, 1);
+            assert_eq!(reputation.rating_sum, 4);
+            assert_eq!(reputation.recent_average, 400);
+
+            let reviews = contract.get_reviews_for_user(seller_id, 0, 10);
+            assert_eq!(reviews.len(), 1);
+            assert_eq!(reviews[0].rating, 4);
+            assert!(contract.get_reviews_for_user(seller_id, 1, 10).is_empty());
+
+            let buyer_id = contract.get_request(request_id).unwrap().buyer_id;
+            assert_eq!(
+                contract
+                    .get_user_reputation(buyer_id)
+                    .unwrap()
+                    .recent_average,
+                500
+            );
         }
 
         #[test]
-        // remove request
-        fn test_remove_request() {
+        fn test_only_owner_appoints_arbitrators() {
             set_buyer_env();
-            let mut contract = Marketplace::new();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+
+            set_seller_env();
+            assert_eq!(
+                contract.add_arbitrator(accounts.django),
+                Err(MarketplaceError::MissingRole)
+            );
+            assert!(contract.get_arbitrators().is_empty());
+        }
 
+        #[test]
+        fn test_pause_and_circuit_breakers() {
+            set_buyer_env();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
             // Create a buyer and a request
-            let buyer_name = "Bob".to_string();
-            let buyer_phone = "0987654321".to_string();
-            let latitude = 98765;
-            let longitude = 56789;
-            let buyer_account_type = AccountType::Buyer;
             contract
                 .create_user(
-                    buyer_name.clone(),
-                    buyer_phone.clone(),
-                    latitude,
-                    longitude,
-                    buyer_account_type,
+                    "Alice".to_string(),
+                    masked_phone("0987654321"),
+                    98765,
+                    56789,
+                    AccountType::Buyer,
                 )
                 .unwrap();
-
-            let request_name = "Request 1".to_string();
-            let request_description = "Need this item".to_string();
-            let images = vec!["image1".to_string()];
             contract
                 .create_request(
-                    request_name.clone(),
-                    request_description.clone(),
-                    images.clone(),
-                    latitude,
-                    longitude,
+                    "Request 1".to_string(),
+                    "Need this item".to_string(),
+                    vec!["image1".to_string()],
+                    98765,
+                    56789,
+                    RequestParams::default(),
                 )
                 .unwrap();
 
-            // Remove the request
-            let request_id = 1;
-            let result = contract.delete_request(request_id);
-            assert!(result.is_ok());
+            // Create a seller, a store and an offer
+            set_seller_env();
+            contract
+                .create_user(
+                    "Bob".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                    AccountType::Seller,
+                )
+                .unwrap();
+            contract
+                .create_store(
+                    "My Store".to_string(),
+                    "Best Store".to_string(),
+                    masked_phone("1234567890"),
+                    98765,
+                    56789,
+                )
+                .unwrap();
+            contract
+                .create_offer(
+                    contract.request_counter,
+                    100,
+                    vec!["offer_image1".to_string()],
+                    contract.store_counter,
+                )
+                .unwrap();
+            set_buyer_env();
+            let request_id = contract.request_counter;
+            let offer_id = contract.offer_counter;
 
-            // Check if the request was removed
-            let request = contract.get_request(request_id);
-            assert_eq!(request, None);
+            set_seller_env();
+            assert_eq!(contract.pause(), Err(MarketplaceError::MissingRole));
+
+            // Stopping new offers leaves the rest of the flow running
+            set_buyer_env();
+            contract
+                .set_circuit_breaker(Operation::Offers, true)
+                .unwrap();
+            assert!(contract.get_circuit_breakers().offers);
+            set_seller_env();
+            assert_eq!(
+                contract.update_offer(offer_id, 90, Vec::new()),
+                Err(MarketplaceError::ContractPaused)
+            );
+            contract.toggle_location(false).unwrap();
+            set_buyer_env();
+            assert_eq!(
+                contract.counter_offer(offer_id, 90),
+                Err(MarketplaceError::ContractPaused)
+            );
+
+            // A full pause stops everything but administration and the exits
+            // before a request locks
+            contract.pause().unwrap();
+            assert!(contract.is_paused());
+            fund_buyer();
+            assert_eq!(
+                ink::env::pay_with_call!(contract.accept_offer(offer_id, 100), 100),
+                Err(MarketplaceError::ContractPaused)
+            );
+            contract.add_category("Tools".to_string()).unwrap();
+
+            contract.unpause().unwrap();
+            assert!(contract.get_circuit_breakers().offers);
+            contract
+                .set_circuit_breaker(Operation::Acceptances, true)
+                .unwrap();
+            assert_eq!(
+                ink::env::pay_with_call!(contract.accept_offer(offer_id, 100), 100),
+                Err(MarketplaceError::ContractPaused)
+            );
+            contract
+                .set_circuit_breaker(Operation::Acceptances, false)
+                .unwrap();
+            ink::env::pay_with_call!(contract.accept_offer(offer_id, 100), 100).unwrap();
+
+            contract.pause().unwrap();
+            contract.cancel_request(request_id).unwrap();
+            assert_eq!(
+                contract.get_request(request_id).unwrap().lifecycle,
+                RequestLifecycle::Cancelled
+            );
         }
 
         #[test]
-        pub fn toggle_location() {
+        fn test_roles_are_granted_and_revoked() {
             set_buyer_env();
-            let mut contract = Marketplace::new();
+            let mut contract = Marketplace::new(MarketplaceConfig::default()).unwrap();
+            let accounts = ink::env::test::default_accounts::<DefaultEnvironment>();
+            assert_eq!(contract.get_owner(), accounts.alice);
+            assert!(contract.has_role(Role::FeeManager, accounts.alice));
+            assert!(!contract.has_role(Role::FeeManager, accounts.bob));
+
+            // Only the owner makes admins, who then grant the other roles
+            contract.grant_role(Role::Admin, accounts.bob).unwrap();
+            set_seller_env();
+            assert_eq!(
+                contract.grant_role(Role::Admin, accounts.charlie),
+                Err(MarketplaceError::NotOwner)
+            );
+            contract
+                .grant_role(Role::FeeManager, accounts.charlie)
+                .unwrap();
+            contract.add_arbitrator(accounts.django).unwrap();
+            contract.add_arbitrator(accounts.django).unwrap();
+            assert_eq!(contract.get_arbitrators(), vec![accounts.django]);
+            assert!(contract.has_role(Role::Arbitrator, accounts.django));
+            // Arbitration is left to the appointed panel
+            assert!(!contract.has_role(Role::Arbitrator, accounts.alice));
+            assert!(!contract.has_role(Role::Arbitrator, accounts.bob));
+
+            ink::env::test::set_caller::<DefaultEnvironment>(accounts.charlie);
+            contract.set_cancellation_fee_bps(500).unwrap();
+            assert_eq!(
+                contract.add_category("Tools".to_string()),
+                Err(MarketplaceError::MissingRole)
+            );
+            assert_eq!(
+                contract.revoke_role(Role::Arbitrator, accounts.django),
+                Err(MarketplaceError::MissingRole)
+            );
 
-            // Create a buyer and a request
-            let buyer_name = "Bob".to_string();
-            let buyer_phone = "0987654321".to_string();
-            let latitude = 98765;
-            let longitude = 56789;
-            let buyer_account_type = AccountType::Buyer;
+            set_seller_env();
             contract
-                .create_user(
-                    buyer_name.clone(),
-                    buyer_phone.clone(),
-                    latitude,
-                    longitude,
-                    buyer_account_type,
-                )
+                .revoke_role(Role::FeeManager, accounts.charlie)
                 .unwrap();
+            contract.remove_arbitrator(accounts.django).unwrap();
+            contract.remove_arbitrator(accounts.django).unwrap();
+            assert!(contract.get_arbitrators().is_empty());
+            ink::env::test::set_caller::<DefaultEnvironment>(accounts.charlie);
+            assert_eq!(
+                contract.set_cancellation_fee_bps(500),
+                Err(MarketplaceError::MissingRole)
+            );
 
-            let enable_location = contract.get_location_preference();
-            assert_eq!(enable_location, true);
-            assert_ne!(enable_location, false);
+            // Ownership moves with every implicit role
+            set_buyer_env();
+            assert_eq!(
+                contract.transfer_ownership(accounts.alice),
+                Err(MarketplaceError::AlreadyOwner)
+            );
+            contract.transfer_ownership(accounts.eve).unwrap();
+            assert!(!contract.has_role(Role::Moderator, accounts.alice));
+            assert!(contract.has_role(Role::Moderator, accounts.eve));
+        }
+    }
 
-            // Toggle location
-            contract.toggle_location(true).unwrap();
-            let enable_location = contract.get_location_preference();
-            assert_eq!(enable_location, true);
+    #[cfg(all(test, feature = "e2e-tests"))]
+    mod e2e_tests {
+        use super::*;
+        use ink_e2e::ContractsBackend;
+        use mock_psp22::{MockPsp22, MockPsp22Ref};
+
+        type E2EResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+        #[ink_e2e::test]
+        async fn psp22_offer_is_escrowed_and_refunded<Client: E2EBackend>(
+            mut client: Client,
+        ) -> E2EResult<()> {
+            let alice = ink_e2e::account_id(ink_e2e::AccountKeyring::Alice);
+
+            // Deploy the token with the whole supply minted to the buyer
+            let mut token_constructor = MockPsp22Ref::new(1_000_000);
+            let token = client
+                .instantiate("mock_psp22", &ink_e2e::alice(), &mut token_constructor)
+                .submit()
+                .await
+                .expect("token instantiation failed");
+            let mut token_call = token.call_builder::<MockPsp22>();
+
+            let mut constructor = MarketplaceRef::new(MarketplaceConfig::default());
+            let marketplace = client
+                .instantiate("marketplace", &ink_e2e::alice(), &mut constructor)
+                .submit()
+                .await
+                .expect("marketplace instantiation failed");
+            let mut call = marketplace.call_builder::<Marketplace>();
+
+            // Buyer posts a request settled in the token
+            let create_buyer = call.create_user(
+                "Alice".to_string(),
+                MaskedPhone {
+                    phone: "********21".to_string(),
+                    commitment: Hash::from([1; 32]),
+                },
+                98765,
+                56789,
+                AccountType::Buyer,
+            );
+            client
+                .call(&ink_e2e::alice(), &create_buyer)
+                .submit()
+                .await?;
+            let create_request = call.create_request(
+                "Request 1".to_string(),
+                "Need this item".to_string(),
+                Vec::new(),
+                98765,
+                56789,
+                RequestParams {
+                    payment_asset: PaymentAsset::Psp22(token.account_id),
+                    ..Default::default()
+                },
+            );
+            client
+                .call(&ink_e2e::alice(), &create_request)
+                .submit()
+                .await?;
+
+            // Seller quotes a price in the token
+            let create_seller = call.create_user(
+                "Bob".to_string(),
+                MaskedPhone {
+                    phone: "********90".to_string(),
+                    commitment: Hash::from([0; 32]),
+                },
+                98765,
+                56789,
+                AccountType::Seller,
+            );
+            client
+                .call(&ink_e2e::bob(), &create_seller)
+                .submit()
+                .await?;
+            let create_store = call.create_store(
+                "My Store".to_string(),
+                "Best Store".to_string(),
+                MaskedPhone {
+                    phone: "********90".to_string(),
+                    commitment: Hash::from([0; 32]),
+                },
+                98765,
+                56789,
+            );
+            client.call(&ink_e2e::bob(), &create_store).submit().await?;
+            let create_offer = call.create_offer(1, 500, Vec::new(), 1);
+            client.call(&ink_e2e::bob(), &create_offer).submit().await?;
+
+            // Buyer approves the marketplace and accepts the offer
+            let approve = token_call.approve(marketplace.account_id, 500);
+            client.call(&ink_e2e::alice(), &approve).submit().await?;
+            let accept_offer = call.accept_offer(1, 500);
+            client
+                .call(&ink_e2e::alice(), &accept_offer)
+                .submit()
+                .await?;
+
+            let escrowed = token_call.balance_of(marketplace.account_id);
+            let escrowed = client.call(&ink_e2e::alice(), &escrowed).dry_run().await?;
+            assert_eq!(escrowed.return_value(), 500);
+
+            // Cancelling the request before it locks returns the tokens
+            let cancel_request = call.cancel_request(1);
+            client
+                .call(&ink_e2e::alice(), &cancel_request)
+                .submit()
+                .await?;
+
+            let buyer_balance = token_call.balance_of(alice);
+            let buyer_balance = client
+                .call(&ink_e2e::alice(), &buyer_balance)
+                .dry_run()
+                .await?;
+            assert_eq!(buyer_balance.return_value(), 1_000_000);
 
-            contract.toggle_location(false).unwrap();
-            let enable_location = contract.get_location_preference();
-            assert_eq!(enable_location, false);
+            Ok(())
         }
     }
 }